@@ -1,4 +1,21 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    io::{self, ErrorKind, Read},
+    path::Path,
+};
+
+use protocol::{
+    bus::{BusConfig, HalfDuplexBus},
+    config::DeskConfigBuilder,
+    encoder::encode,
+    keypad::{KeypadMonitor, KeypadState},
+    new_protocol::{BaseCommand, Command, IoError, Register, Source},
+    sequence::{LinkSequences, SequenceEvent},
+    std_io::{PacketReader, StdWriter, TimedCommand},
+};
+
+/// The directories in `data/` that each hold a `controller.csv` and a `desk.csv` capture
+const CAPTURES: &[&str] = &["", "connect", "idle", "one", "up", "two-(and_up)"];
 
 /// A Segment is a segment of Packets that are sent together from one device to another without interruption from the other device (half duplex)
 
@@ -49,19 +66,13 @@ impl<'a> PartialOrd for Packet<'a> {
                 Packet::Desk(first_frame) | Packet::Controller(first_frame),
                 Packet::Desk(second_frame) | Packet::Controller(second_frame),
             ) => first_frame
-                .get(0)?
+                .first()?
                 .time
-                .partial_cmp(&second_frame.get(0)?.time),
+                .partial_cmp(&second_frame.first()?.time),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Source {
-    Desk,
-    Controller,
-}
-
 /// A frame is single parsed uart data packet
 #[derive(Debug, PartialEq)]
 struct Frame {
@@ -115,8 +126,223 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("{segment}");
     }
 
-    // let segments =
+    for capture in CAPTURES {
+        check_capture(capture)?;
+        check_turns(capture)?;
+    }
+
+    Ok(())
+}
+
+/// Plays a capture back as a [`Read`] so it goes through the same [`PacketReader`] as a serial port
+///
+/// Each read gives a single byte so every packet is finished by the frame read last. A parity or
+/// framing error is returned as an error the way a uart reports one.
+struct CaptureReader<'a> {
+    frames: std::slice::Iter<'a, Frame>,
+    last: Option<&'a Frame>,
+    /// Every byte read since this was last taken
+    wire: Vec<u8>,
+}
+impl<'a> CaptureReader<'a> {
+    fn new(frames: &'a [Frame]) -> Self {
+        Self {
+            frames: frames.iter(),
+            last: None,
+            wire: Vec::new(),
+        }
+    }
+}
+impl Read for CaptureReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(frame) = self.frames.next() else {
+            return Ok(0);
+        };
+        self.last = Some(frame);
+        match frame.value {
+            FrameValue::Value(value) => {
+                buf[0] = value;
+                self.wire.push(value);
+                Ok(1)
+            }
+            ref error => Err(io::Error::new(ErrorKind::InvalidData, error.to_string())),
+        }
+    }
+}
+
+/// Reads every packet in a capture through a [`PacketReader`] and re-encodes it, and fails if any
+/// of them don't come back out the same
+fn check_capture(capture: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = Path::new("crates/data-captures/data").join(capture);
+    let mut sequences = LinkSequences::default();
+    let mut keypad = KeypadMonitor::default();
+    let mut keypad_state = KeypadState::Absent;
+    let mut config = DeskConfigBuilder::new();
+    for (file, source) in [
+        ("controller.csv", Source::Controller),
+        ("desk.csv", Source::Desk),
+    ] {
+        let path = dir.join(file);
+        let frames = parse_frames(&path.to_string_lossy())?;
 
+        let mut reader = PacketReader::new(CaptureReader::new(&frames));
+        let (mut decoded, mut total) = (0, 0);
+        while let Some(result) = reader.next() {
+            if let Err(IoError::Io(_)) = result {
+                // A parity or framing error, which spoils the packet it is in
+                reader.reset();
+                continue;
+            }
+            let capture = reader.get_mut();
+            let time = capture.last.map_or(0., |frame| frame.time);
+            // Everything off the wire since the last packet, to check the encoder against
+            let wire = std::mem::take(&mut capture.wire);
+            let TimedCommand {
+                packet_num,
+                command,
+                ..
+            } = match result {
+                Ok(command) => command,
+                Err(error) => {
+                    total += 1;
+                    println!("{}: {time:.4}s: {error}", path.display());
+                    continue;
+                }
+            };
+            total += 1;
+            match sequences.observe(source, packet_num) {
+                SequenceEvent::First | SequenceEvent::InOrder => {}
+                event => println!("{}: {time:.4}s: {event:?}", path.display()),
+            }
+            let now_ms = (time * 1000.) as u64;
+            keypad.observe(&command, now_ms);
+            match command {
+                BaseCommand::HandShake(Command::Reponse(value)) => config.set(value),
+                BaseCommand::Identify(Command::Command(identity)) => {
+                    println!("{}: {time:.4}s: {identity}", path.display())
+                }
+                _ => {}
+            }
+            if keypad.state(now_ms) != keypad_state {
+                keypad_state = keypad.state(now_ms);
+                println!("{}: {time:.4}s: {keypad_state:?}", path.display());
+            }
+
+            let mut encoded = StdWriter(Vec::new());
+            encode(&command, packet_num, &mut encoded)?;
+            let encoded = encoded.into_inner();
+            if wire.ends_with(&encoded) {
+                decoded += 1;
+            } else {
+                println!(
+                    "{}: encoded {command:?} as {encoded:02x?}, captured {wire:02x?}",
+                    path.display()
+                );
+            }
+        }
+        println!(
+            "{}: round tripped {decoded}/{total}, discarded {} bytes",
+            path.display(),
+            reader.discarded()
+        );
+        if decoded != total {
+            return Err(format!(
+                "{}: {} packets didn't round trip",
+                path.display(),
+                total - decoded
+            )
+            .into());
+        }
+    }
+    println!(
+        "{}: controller {:?}",
+        dir.display(),
+        sequences.controller.stats()
+    );
+    println!("{}: desk {:?}", dir.display(), sequences.desk.stats());
+    if Register::ALL
+        .iter()
+        .any(|register| config.get(*register).is_some())
+    {
+        println!("{}: {:?}", dir.display(), config.build());
+    }
+    Ok(())
+}
+
+/// Replays a capture through the keypad's side of a [`HalfDuplexBus`] and reports every packet the
+/// captured keypad started before its turn, and every time the desk sent while the keypad was
+/// still sending
+///
+/// The desk and keypad each have their own wire, so neither is a fault, just a place where the
+/// devices didn't take turns.
+fn check_turns(capture: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = Path::new("crates/data-captures/data").join(capture);
+    let to_us = |frame: &Frame| (frame.time * 1_000_000.) as u64;
+    // Each desk byte, and each keypad packet as a whole when its start tag went out
+    let mut line: Vec<(u64, Option<u8>, Vec<u8>)> = Vec::new();
+    for frame in parse_frames(&dir.join("desk.csv").to_string_lossy())? {
+        if let FrameValue::Value(value) = frame.value {
+            // Frames are timestamped at their start bit, a uart has them a byte later
+            let at_us = to_us(&frame) + BusConfig::CAPTURED.byte_us;
+            line.push((at_us, Some(value), Vec::new()));
+        }
+    }
+    let controller_frames = parse_frames(&dir.join("controller.csv").to_string_lossy())?;
+    for packet in parse_packets(&controller_frames, Source::Controller) {
+        let Packet::Controller(frames) = packet else {
+            continue;
+        };
+        let Some(first) = frames.first() else {
+            continue;
+        };
+        // The packet's frames are between the tags
+        let bytes = frames
+            .iter()
+            .filter_map(|frame| match frame.value {
+                FrameValue::Value(value) => Some(value),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        line.push((to_us(first), None, [&[0xFA], &bytes[..], &[0xFD]].concat()));
+    }
+    line.sort_by_key(|(at_us, _, _)| *at_us);
+
+    let mut bus = HalfDuplexBus::new(BusConfig::CAPTURED);
+    let mut overlap = 0;
+    for (at_us, byte, packet) in line {
+        if let Some(byte) = byte {
+            match bus.observe(byte, at_us) {
+                Some(collision) if overlap == 0 => {
+                    println!(
+                        "{}: {:.4}s: desk sent while the keypad was sending, starting with {:#04x}",
+                        dir.display(),
+                        collision.at_us as f64 / 1_000_000.,
+                        collision.byte
+                    );
+                    overlap = 1;
+                }
+                Some(_) => overlap += 1,
+                None if overlap > 0 => {
+                    println!("{}: overlapped for {overlap} bytes", dir.display());
+                    overlap = 0;
+                }
+                None => {}
+            }
+        } else {
+            let ready_at_us = bus.ready_at(at_us);
+            if ready_at_us > at_us {
+                println!(
+                    "{}: {:.4}s: keypad started {}us before its turn: {packet:02x?}",
+                    dir.display(),
+                    at_us as f64 / 1_000_000.,
+                    ready_at_us - at_us
+                );
+            }
+            // Never refused, the captured keypad sent it anyway
+            let _ = bus.start_transmit(&packet, at_us);
+        }
+    }
+    println!("{}: keypad turns {:?}", dir.display(), bus.stats());
     Ok(())
 }
 
@@ -157,7 +383,7 @@ fn build_segments<'a>(all_packets: &'a [Packet]) -> Vec<Segment<'a>> {
     segments
 }
 
-fn parse_packets(frames: &[Frame], source: Source) -> Vec<Packet> {
+fn parse_packets(frames: &[Frame], source: Source) -> Vec<Packet<'_>> {
     let mut packets = Vec::new();
     let mut start_index = None;
 
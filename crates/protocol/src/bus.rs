@@ -0,0 +1,395 @@
+//! Whose turn it is on the link between the keypad and the desk
+//!
+//! The two sides mostly take turns: one sends a burst of packets back to back and the other only
+//! starts once the line has gone quiet. [`HalfDuplexBus`] follows the link from one side so that
+//! side only starts sending after the other side's burst has ended, and notices when both sides
+//! sent at once anyway.
+//!
+//! The link isn't strictly half-duplex though. Each direction has its own wire in the captures,
+//! and the desk sends its height reports on its own schedule, so the two sides do sometimes send
+//! at once: 4 bytes at 10.057s in the root capture, and 8 each at 1.6995s in `up` and 2.9095s in
+//! `two-(and_up)`. Neither side loses anything when that happens. Waiting for a turn only copies
+//! the captured devices' manners, so it can be turned off with [`BusConfig::take_turns`], and a
+//! [`Collision`] on such a link is just an overlap.
+//!
+//! Feed it every byte read from the line with [`HalfDuplexBus::observe`], and ask
+//! [`HalfDuplexBus::start_transmit`] before writing each packet. Times are microseconds from any
+//! monotonic clock rather than the milliseconds used elsewhere, since a byte takes 87us.
+
+use crate::new_protocol::{END_TAG, ESCAPE, MAX_ESCAPED_LEN, START_TAG};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusConfig {
+    /// How long one byte takes on the wire
+    pub byte_us: u64,
+    /// How long the line has to be quiet after the end of the other side's last byte before this
+    /// side starts
+    pub turnaround_us: u64,
+    /// How long the line is left idle between this side's own packets
+    pub packet_gap_us: u64,
+    /// How long the other side can go quiet partway through a packet before it's treated as
+    /// having given up on it
+    pub stall_us: u64,
+    /// Every byte this side sends is read back, as it is on a single wire
+    pub echo: bool,
+    /// [`HalfDuplexBus::start_transmit`] refuses to start before [`HalfDuplexBus::ready_at`].
+    /// When off, sending always starts straight away and overlaps are still counted
+    pub take_turns: bool,
+}
+impl Default for BusConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+impl BusConfig {
+    pub const DEFAULT: Self = Self {
+        // The captures have a byte every 86.5us to 87.2us, 10 bits at about 115200 baud. Rounded
+        // down so the captured keypad's back to back packets fit
+        byte_us: 86,
+        // Both captured devices answer 130us after the other's last byte ends
+        turnaround_us: 130,
+        // The captured devices send their bursts with no idle time at all between packets.
+        // A byte time is left so a receiver slower than the captured ones gets a moment between
+        // packets, which costs well under a millisecond a burst
+        packet_gap_us: 87,
+        // Over 10 bytes. Bytes in a packet never arrive more than one byte time apart
+        stall_us: 1000,
+        echo: false,
+        take_turns: true,
+    };
+
+    /// What the captured devices do, for replaying the captures. They send bursts back to back
+    /// and mostly wait their turn, but not always
+    pub const CAPTURED: Self = Self {
+        packet_gap_us: 0,
+        take_turns: false,
+        ..Self::DEFAULT
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Turn {
+    /// Nobody is sending, this side may start
+    Idle,
+    /// This side's bytes are on the wire until `until_us`
+    Transmitting { until_us: u64 },
+    /// The other side is sending, or hasn't been quiet for long enough
+    Receiving,
+}
+
+/// Both sides were sending at once. Harmless when each direction has its own wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Collision {
+    pub at_us: u64,
+    /// What was read. With [`BusConfig::echo`] this side sent something else
+    pub byte: u8,
+}
+
+/// It isn't this side's turn yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusBusy {
+    /// The earliest this side can start, if the other side doesn't send anything before then
+    pub ready_at_us: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BusStats {
+    /// Bytes from the other side
+    pub received: u32,
+    /// Packets this side has started sending
+    pub sent: u32,
+    /// Times [`HalfDuplexBus::start_transmit`] was refused, or would have been without
+    /// [`BusConfig::take_turns`]
+    pub deferred: u32,
+    /// Bytes read while this side was sending
+    pub collisions: u32,
+}
+
+/// One side's view of the shared line
+#[derive(Debug, Clone)]
+pub struct HalfDuplexBus {
+    config: BusConfig,
+    /// When the other side's last byte arrived
+    last_rx_us: Option<u64>,
+    /// The other side has sent a start tag but not its end tag
+    rx_in_packet: bool,
+    rx_escaped: bool,
+    /// When this side's last packet finishes going out
+    tx_until_us: Option<u64>,
+    /// What should be read back while this side is sending with echo on
+    echo: [u8; MAX_ESCAPED_LEN],
+    echo_len: usize,
+    echo_pos: usize,
+    stats: BusStats,
+}
+impl Default for HalfDuplexBus {
+    fn default() -> Self {
+        Self::new(BusConfig::DEFAULT)
+    }
+}
+impl HalfDuplexBus {
+    pub fn new(config: BusConfig) -> Self {
+        Self {
+            config,
+            last_rx_us: None,
+            rx_in_packet: false,
+            rx_escaped: false,
+            tx_until_us: None,
+            echo: [0; MAX_ESCAPED_LEN],
+            echo_len: 0,
+            echo_pos: 0,
+            stats: BusStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &BusStats {
+        &self.stats
+    }
+
+    /// Records a byte read from the line, reporting it if this side was sending at the time
+    ///
+    /// `now_us` is when the whole byte had arrived.
+    pub fn observe(&mut self, byte: u8, now_us: u64) -> Option<Collision> {
+        let transmitting = self.tx_until_us.is_some_and(|until| now_us < until);
+        if self.config.echo && self.echo_pos < self.echo_len {
+            if byte == self.echo[self.echo_pos] {
+                self.echo_pos += 1;
+                return None;
+            }
+            // The rest of the echo will be garbled too
+            self.echo_len = 0;
+        } else if !transmitting {
+            self.receive(byte, now_us);
+            return None;
+        }
+        self.receive(byte, now_us);
+        self.stats.collisions += 1;
+        Some(Collision {
+            at_us: now_us,
+            byte,
+        })
+    }
+
+    pub fn turn(&self, now_us: u64) -> Turn {
+        match self.tx_until_us {
+            Some(until_us) if now_us < until_us => Turn::Transmitting { until_us },
+            _ if self.ready_at(now_us) > now_us => Turn::Receiving,
+            _ => Turn::Idle,
+        }
+    }
+
+    /// The earliest this side can start sending, if nothing else arrives before then
+    pub fn ready_at(&self, now_us: u64) -> u64 {
+        let after_tx = self
+            .tx_until_us
+            .map_or(0, |until| until + self.config.packet_gap_us);
+        let after_rx = self.last_rx_us.map_or(0, |last| {
+            // A packet that stopped partway might still be finished
+            let quiet = if self.rx_in_packet {
+                self.config.stall_us.max(self.config.turnaround_us)
+            } else {
+                self.config.turnaround_us
+            };
+            last + quiet
+        });
+        now_us.max(after_tx).max(after_rx)
+    }
+
+    /// Claims the line for `packet`, the bytes that are about to be written
+    ///
+    /// Nothing is recorded if it isn't this side's turn, so write nothing and try again at
+    /// [`BusBusy::ready_at_us`] or later. Never refuses without [`BusConfig::take_turns`].
+    pub fn start_transmit(&mut self, packet: &[u8], now_us: u64) -> Result<(), BusBusy> {
+        let ready_at_us = self.ready_at(now_us);
+        if ready_at_us > now_us {
+            self.stats.deferred += 1;
+            if self.config.take_turns {
+                return Err(BusBusy { ready_at_us });
+            }
+        }
+        self.tx_until_us = Some(now_us + packet.len() as u64 * self.config.byte_us);
+        let len = packet.len().min(MAX_ESCAPED_LEN);
+        self.echo[..len].copy_from_slice(&packet[..len]);
+        self.echo_len = len;
+        self.echo_pos = 0;
+        self.stats.sent += 1;
+        Ok(())
+    }
+
+    fn receive(&mut self, byte: u8, now_us: u64) {
+        self.stats.received += 1;
+        self.last_rx_us = Some(now_us);
+        if self.rx_in_packet && self.rx_escaped {
+            self.rx_escaped = false;
+            return;
+        }
+        match byte {
+            START_TAG => self.rx_in_packet = true,
+            END_TAG => self.rx_in_packet = false,
+            ESCAPE => self.rx_escaped = self.rx_in_packet,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BYTE_US: u64 = BusConfig::DEFAULT.byte_us;
+    const TURNAROUND_US: u64 = BusConfig::DEFAULT.turnaround_us;
+    const STALL_US: u64 = BusConfig::DEFAULT.stall_us;
+    const KEY_PRESS: [u8; 8] = [0xFA, 0x17, 0x03, 0x01, 0x06, 0xCF, 0xDC, 0xFD];
+
+    /// Reads `bytes` back to back, the first at `from_us`. Gives when the last arrived
+    fn receive(bus: &mut HalfDuplexBus, bytes: &[u8], from_us: u64) -> u64 {
+        let mut at_us = from_us;
+        for (index, &byte) in bytes.iter().enumerate() {
+            at_us = from_us + index as u64 * BYTE_US;
+            assert_eq!(bus.observe(byte, at_us), None);
+        }
+        at_us
+    }
+
+    #[test]
+    fn idle_at_first() {
+        let mut bus = HalfDuplexBus::default();
+        assert_eq!(bus.turn(0), Turn::Idle);
+        assert_eq!(bus.ready_at(0), 0);
+        assert_eq!(bus.start_transmit(&KEY_PRESS, 0), Ok(()));
+        assert_eq!(
+            bus.turn(0),
+            Turn::Transmitting {
+                until_us: 8 * BYTE_US
+            }
+        );
+    }
+
+    #[test]
+    fn waits_for_turnaround() {
+        let mut bus = HalfDuplexBus::default();
+        let last_us = receive(&mut bus, &KEY_PRESS, 1000);
+        assert_eq!(bus.turn(last_us), Turn::Receiving);
+        assert_eq!(bus.ready_at(last_us), last_us + TURNAROUND_US);
+        assert_eq!(
+            bus.start_transmit(&KEY_PRESS, last_us + 1),
+            Err(BusBusy {
+                ready_at_us: last_us + TURNAROUND_US
+            })
+        );
+        assert_eq!(bus.turn(last_us + TURNAROUND_US), Turn::Idle);
+        assert_eq!(
+            bus.start_transmit(&KEY_PRESS, last_us + TURNAROUND_US),
+            Ok(())
+        );
+        assert_eq!(
+            *bus.stats(),
+            BusStats {
+                received: 8,
+                sent: 1,
+                deferred: 1,
+                collisions: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn waits_for_stalled_packet() {
+        let mut bus = HalfDuplexBus::default();
+        // Cut off partway, it might still be finished
+        let last_us = receive(&mut bus, &KEY_PRESS[..4], 0);
+        assert_eq!(bus.ready_at(last_us), last_us + STALL_US);
+        assert_eq!(
+            bus.start_transmit(&KEY_PRESS, last_us + TURNAROUND_US),
+            Err(BusBusy {
+                ready_at_us: last_us + STALL_US
+            })
+        );
+        assert_eq!(bus.start_transmit(&KEY_PRESS, last_us + STALL_US), Ok(()));
+    }
+
+    #[test]
+    fn escaped_end_tag_continues_packet() {
+        let mut bus = HalfDuplexBus::default();
+        // The checksum is an escaped end tag
+        let packet = [0xFA, 0x01, 0xA0, 0x04, 0x01, 0x59, 0xFE, 0xFD, 0xFD];
+        let escaped_us = receive(&mut bus, &packet[..8], 0);
+        assert_eq!(bus.ready_at(escaped_us), escaped_us + STALL_US);
+        let last_us = receive(&mut bus, &packet[8..], escaped_us + BYTE_US);
+        assert_eq!(bus.ready_at(last_us), last_us + TURNAROUND_US);
+
+        // An escape outside of a packet is noise and doesn't escape the next start tag
+        let last_us = receive(&mut bus, &[0xFE, 0xFA], last_us + 1000);
+        assert_eq!(bus.ready_at(last_us), last_us + STALL_US);
+    }
+
+    #[test]
+    fn leaves_gap_between_own_packets() {
+        let mut bus = HalfDuplexBus::default();
+        bus.start_transmit(&KEY_PRESS, 0).unwrap();
+        let until_us = 8 * BYTE_US;
+        let gap_us = BusConfig::DEFAULT.packet_gap_us;
+        assert!(gap_us > 0);
+        assert_eq!(
+            bus.start_transmit(&KEY_PRESS, until_us),
+            Err(BusBusy {
+                ready_at_us: until_us + gap_us
+            })
+        );
+        assert_eq!(bus.start_transmit(&KEY_PRESS, until_us + gap_us), Ok(()));
+
+        let mut bus = HalfDuplexBus::new(BusConfig::CAPTURED);
+        bus.start_transmit(&KEY_PRESS, 0).unwrap();
+        assert_eq!(bus.start_transmit(&KEY_PRESS, until_us), Ok(()));
+    }
+
+    #[test]
+    fn reports_collisions() {
+        let mut bus = HalfDuplexBus::default();
+        bus.start_transmit(&KEY_PRESS, 0).unwrap();
+        assert_eq!(
+            bus.observe(0xFA, BYTE_US),
+            Some(Collision {
+                at_us: BYTE_US,
+                byte: 0xFA
+            })
+        );
+        // Still followed once this side has finished
+        assert_eq!(bus.observe(0x03, 8 * BYTE_US), None);
+        assert_eq!(bus.ready_at(8 * BYTE_US), 8 * BYTE_US + STALL_US);
+        assert_eq!(bus.stats().collisions, 1);
+    }
+
+    #[test]
+    fn ignores_echo() {
+        let mut bus = HalfDuplexBus::new(BusConfig {
+            echo: true,
+            ..BusConfig::DEFAULT
+        });
+        bus.start_transmit(&KEY_PRESS, 0).unwrap();
+        for (index, &byte) in KEY_PRESS.iter().enumerate() {
+            assert_eq!(bus.observe(byte, (index as u64 + 1) * BYTE_US), None);
+        }
+        assert_eq!(bus.stats().received, 0);
+
+        // Garbled by the other side sending at the same time
+        bus.start_transmit(&KEY_PRESS, 20 * BYTE_US).unwrap();
+        assert_eq!(bus.observe(0xFA, 21 * BYTE_US), None);
+        assert!(bus.observe(0x00, 22 * BYTE_US).is_some());
+        assert!(bus.observe(0x03, 23 * BYTE_US).is_some());
+        assert_eq!(bus.stats().collisions, 2);
+    }
+
+    #[test]
+    fn without_turns() {
+        let mut bus = HalfDuplexBus::new(BusConfig {
+            take_turns: false,
+            ..BusConfig::DEFAULT
+        });
+        let last_us = receive(&mut bus, &KEY_PRESS[..4], 0);
+        assert_eq!(bus.start_transmit(&KEY_PRESS, last_us + 1), Ok(()));
+        assert!(bus.observe(0x01, last_us + BYTE_US).is_some());
+        assert_eq!(bus.stats().deferred, 1);
+        assert_eq!(bus.stats().collisions, 1);
+    }
+}
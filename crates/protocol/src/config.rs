@@ -0,0 +1,252 @@
+//! The desk configuration the keypad reads during the handshake
+//!
+//! After identifying itself the keypad asks for each [`Register`] in turn and the desk answers
+//! one packet at a time. [`DeskConfigBuilder`] collects the answers as they arrive and
+//! [`DeskConfigBuilder::build`] gives the [`DeskConfig`] once every register has been seen.
+
+use crate::{
+    height::HeightLimits,
+    new_protocol::{ProtocolError, ProtocolResult, Register, RegisterValue, Units},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeskConfig {
+    /// What the desk can physically move to, registers 0x21 and 0x22
+    pub limits: HeightLimits,
+    /// What the user has limited the desk to, registers 0x73 and 0x72
+    pub user_limits: HeightLimits,
+    pub units: Units,
+    pub unknown_13: u16,
+    pub unknown_14: u16,
+    pub unknown_15: u16,
+}
+impl Default for DeskConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+impl DeskConfig {
+    /// What the captured desk answers with
+    pub const DEFAULT: Self = Self {
+        limits: HeightLimits::DEFAULT,
+        user_limits: HeightLimits::DEFAULT,
+        units: Units::Centimetres,
+        unknown_13: 1,
+        unknown_14: 1,
+        unknown_15: 1,
+    };
+
+    /// The answer the desk gives when asked for `register`
+    pub fn value(&self, register: Register) -> RegisterValue {
+        match register {
+            Register::Unknown13 => RegisterValue::Unknown13(self.unknown_13),
+            Register::Unknown14 => RegisterValue::Unknown14(self.unknown_14),
+            Register::Unknown15 => RegisterValue::Unknown15(self.unknown_15),
+            Register::MinHeight => RegisterValue::MinHeight(self.limits.min),
+            Register::MaxHeight => RegisterValue::MaxHeight(self.limits.max),
+            Register::Units => RegisterValue::Units(self.units),
+            Register::UserMaxHeight => RegisterValue::UserMaxHeight(self.user_limits.max),
+            Register::UserMinHeight => RegisterValue::UserMinHeight(self.user_limits.min),
+        }
+    }
+
+    /// The heights the desk will actually move between
+    pub fn effective_limits(&self) -> HeightLimits {
+        HeightLimits {
+            min: self.limits.min.max(self.user_limits.min),
+            max: self.limits.max.min(self.user_limits.max),
+        }
+    }
+}
+
+/// Collects register answers as they come in
+#[derive(Debug, Clone, Default)]
+pub struct DeskConfigBuilder {
+    values: [Option<RegisterValue>; Register::ALL.len()],
+}
+impl DeskConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn index(register: Register) -> usize {
+        Register::ALL
+            .iter()
+            .position(|r| *r == register)
+            .expect("every register is in Register::ALL")
+    }
+
+    /// Records an answer, replacing any earlier answer for the same register
+    pub fn set(&mut self, value: RegisterValue) {
+        self.values[Self::index(value.register())] = Some(value);
+    }
+
+    pub fn get(&self, register: Register) -> Option<RegisterValue> {
+        self.values[Self::index(register)]
+    }
+
+    /// The first register, in the order the keypad asks, that hasn't been answered
+    pub fn next_missing(&self) -> Option<Register> {
+        Register::ALL
+            .into_iter()
+            .find(|register| self.get(*register).is_none())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_missing().is_none()
+    }
+
+    pub fn build(&self) -> ProtocolResult<DeskConfig> {
+        let mut config = DeskConfig::DEFAULT;
+        for register in Register::ALL {
+            match self
+                .get(register)
+                .ok_or(ProtocolError::MissingRegister(register))?
+            {
+                RegisterValue::Unknown13(raw) => config.unknown_13 = raw,
+                RegisterValue::Unknown14(raw) => config.unknown_14 = raw,
+                RegisterValue::Unknown15(raw) => config.unknown_15 = raw,
+                RegisterValue::MinHeight(height) => config.limits.min = height,
+                RegisterValue::MaxHeight(height) => config.limits.max = height,
+                RegisterValue::Units(units) => config.units = units,
+                RegisterValue::UserMaxHeight(height) => config.user_limits.max = height,
+                RegisterValue::UserMinHeight(height) => config.user_limits.min = height,
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        height::Height,
+        new_protocol::{tests::packet, BaseCommand, Command},
+    };
+
+    /// The desk's answers in the connect capture, in the order the keypad asked for them
+    const CAPTURED_ANSWERS: [[u8; 4]; 8] = [
+        [0x16, 0x13, 0x00, 0x01],
+        [0x16, 0x14, 0x00, 0x01],
+        [0x16, 0x15, 0x00, 0x01],
+        [0x16, 0x21, 0x02, 0x8A],
+        [0x16, 0x22, 0x04, 0xE2],
+        [0x16, 0x23, 0x00, 0x00],
+        [0x16, 0x72, 0x04, 0xE2],
+        [0x16, 0x73, 0x02, 0x8A],
+    ];
+
+    fn captured_answers() -> impl Iterator<Item = RegisterValue> {
+        CAPTURED_ANSWERS
+            .into_iter()
+            .enumerate()
+            .map(|(index, body)| {
+                let packet = packet(&body, 0x1796 + index as u16);
+                match BaseCommand::decode(&packet.as_packet()).unwrap() {
+                    BaseCommand::HandShake(Command::Reponse(value)) => value,
+                    command => panic!("{command:?}"),
+                }
+            })
+    }
+
+    fn limits(min: u16, max: u16) -> HeightLimits {
+        HeightLimits {
+            min: Height::from_mm(min),
+            max: Height::from_mm(max),
+        }
+    }
+
+    #[test]
+    fn builds_the_captured_config() {
+        let mut builder = DeskConfigBuilder::new();
+        assert_eq!(builder.next_missing(), Some(Register::Unknown13));
+        assert!(matches!(
+            builder.build(),
+            Err(ProtocolError::MissingRegister(Register::Unknown13))
+        ));
+        for (value, register) in captured_answers().zip(Register::ALL) {
+            assert_eq!(builder.next_missing(), Some(register));
+            assert!(!builder.is_complete());
+            builder.set(value);
+            assert_eq!(builder.get(register), Some(value));
+        }
+        assert_eq!(builder.next_missing(), None);
+        assert!(builder.is_complete());
+        let config = builder.build().unwrap();
+        assert_eq!(config, DeskConfig::DEFAULT);
+        for (value, register) in captured_answers().zip(Register::ALL) {
+            assert_eq!(config.value(register), value);
+        }
+    }
+
+    #[test]
+    fn answers_in_any_order() {
+        let mut builder = DeskConfigBuilder::new();
+        let answers: std::vec::Vec<_> = captured_answers().collect();
+        for value in answers.into_iter().rev() {
+            builder.set(value);
+        }
+        // A later answer replaces an earlier one
+        builder.set(RegisterValue::MaxHeight(Height::from_mm(1200)));
+        let config = builder.build().unwrap();
+        assert_eq!(config.limits, limits(650, 1200));
+        assert_eq!(config.user_limits, limits(650, 1250));
+    }
+
+    #[test]
+    fn next_missing_follows_the_keypad() {
+        // The first register the keypad asks for that is missing, not the first one set
+        let mut builder = DeskConfigBuilder::new();
+        builder.set(RegisterValue::UserMinHeight(Height::from_mm(700)));
+        builder.set(RegisterValue::Unknown13(1));
+        assert_eq!(builder.next_missing(), Some(Register::Unknown14));
+        assert!(matches!(
+            builder.build(),
+            Err(ProtocolError::MissingRegister(Register::Unknown14))
+        ));
+    }
+
+    #[test]
+    fn every_register_has_a_slot() {
+        // Every register the decoder accepts, so the builder can never be handed one it can't hold
+        let registers = (0..=u8::MAX).filter_map(|byte| Register::try_from(byte).ok());
+        for (count, register) in registers.enumerate() {
+            assert_eq!(Register::ALL[DeskConfigBuilder::index(register)], register);
+            assert!(count < Register::ALL.len());
+        }
+    }
+
+    #[test]
+    fn effective_limits() {
+        let mut config = DeskConfig::DEFAULT;
+        assert_eq!(config.effective_limits(), HeightLimits::DEFAULT);
+        // Tighter user limits win
+        config.user_limits = limits(700, 1100);
+        assert_eq!(config.effective_limits(), limits(700, 1100));
+        // Looser ones can't take the desk past what it can do
+        config.user_limits = limits(600, 1300);
+        assert_eq!(config.effective_limits(), HeightLimits::DEFAULT);
+        // Or one of each
+        config.user_limits = limits(600, 1100);
+        assert_eq!(config.effective_limits(), limits(650, 1100));
+    }
+
+    #[test]
+    fn units() {
+        let mut builder = DeskConfigBuilder::new();
+        for value in captured_answers() {
+            builder.set(value);
+        }
+        assert_eq!(builder.build().unwrap().units, Units::Centimetres);
+        // Anything but 0 is kept so it can be sent back as it was
+        builder.set(RegisterValue::from_raw(Register::Units, 1));
+        let config = builder.build().unwrap();
+        assert_eq!(config.units, Units::Other(1));
+        assert_eq!(
+            config.value(Register::Units),
+            RegisterValue::Units(Units::Other(1))
+        );
+        assert_eq!(u16::from(config.units), 1);
+    }
+}
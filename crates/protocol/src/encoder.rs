@@ -0,0 +1,155 @@
+//! Turns commands into complete packets that can be sent on the wire
+//!
+//! A packet on the wire is
+//! `0xFA | prefix | command id | payload.. | packet number (u16) | checksum | 0xFD`
+//! where the checksum is the xor of everything between the start tag and the checksum. Any start
+//! tag, end tag or escape byte between the tags is sent with an escape byte in front of it.
+
+use crate::new_protocol::{
+    IoError, PacketBuf, PacketBytes, ProtocolError, ProtocolResult, Write, Writeable, END_TAG,
+    ESCAPE, MAX_PACKET_LEN, START_TAG,
+};
+
+/// Collects the unescaped packet so the checksum can be inserted before it is sent
+struct PacketWriter {
+    buf: [u8; MAX_PACKET_LEN],
+    len: usize,
+}
+impl Write for PacketWriter {
+    type Error = ProtocolError;
+
+    fn write_all(&mut self, buf: &[u8]) -> ProtocolResult<()> {
+        let end = self.len + buf.len();
+        let Some(dest) = self.buf.get_mut(self.len..end) else {
+            return Err(ProtocolError::PacketTooLong(PacketBytes::new(
+                &self.buf[..self.len],
+            )));
+        };
+        dest.copy_from_slice(buf);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Writes `command` to `writer` as a complete packet numbered `packet_num`
+///
+/// `command` is anything that writes a prefix, command id and payload. Usually a [`BaseCommand`]
+/// or a [`Command`]. Nothing is written if the packet can't be built.
+///
+/// [`BaseCommand`]: crate::new_protocol::BaseCommand
+/// [`Command`]: crate::new_protocol::Command
+pub fn encode<C: Writeable, W: Write>(
+    command: &C,
+    packet_num: u16,
+    writer: &mut W,
+) -> Result<(), IoError<W::Error>> {
+    let packet = encode_packet(command, packet_num)?;
+    write_escaped(packet.as_bytes(), writer).map_err(IoError::Io)
+}
+
+/// Builds the unescaped packet for `command` with its checksum filled in
+pub fn encode_packet<C: Writeable>(command: &C, packet_num: u16) -> ProtocolResult<PacketBuf> {
+    let mut packet_writer = PacketWriter {
+        buf: [0; MAX_PACKET_LEN],
+        len: 0,
+    };
+    packet_writer.write_all(&[START_TAG])?;
+    command.write_to(&mut packet_writer)?;
+    packet_num.write_to(&mut packet_writer)?;
+    // The checksum is filled in once everything before it has been written
+    packet_writer.write_all(&[0, END_TAG])?;
+    let mut packet = PacketBuf::new(&packet_writer.buf[..packet_writer.len])?;
+    packet.insert_checksum();
+    Ok(packet)
+}
+
+/// Writes an unescaped packet that still has its tags, escaping everything between them
+pub(crate) fn write_escaped<W: Write>(packet: &[u8], writer: &mut W) -> Result<(), W::Error> {
+    let inner = &packet[1..packet.len() - 1];
+    writer.write_all(&[START_TAG])?;
+    for &byte in inner {
+        match byte {
+            START_TAG | END_TAG | ESCAPE => writer.write_all(&[ESCAPE, byte])?,
+            byte => writer.write_all(&[byte])?,
+        }
+    }
+    writer.write_all(&[END_TAG])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        height::Height,
+        new_protocol::{
+            tests::{wire, Wire},
+            BaseCommand, ChangeHeight, ChangeHeightState, Command, ControllerState, ReportHeight,
+        },
+    };
+
+    #[test]
+    fn captured_key_press() {
+        let command =
+            BaseCommand::ChangeHeight(Command::Command(ChangeHeight::Up(ChangeHeightState::Start)));
+        assert_eq!(
+            wire(&command, 0x06CF),
+            [0xFA, 0x17, 0x03, 0x01, 0x06, 0xCF, 0xDC, 0xFD]
+        );
+    }
+
+    #[test]
+    fn escapes_start_tag() {
+        let command = Command::Command(ReportHeight::new(Height::from_mm(0x02FA)));
+        assert_eq!(
+            wire(&command, 0x0D31),
+            [0xFA, 0x03, 0x00, 0x01, 0x02, 0xFE, 0xFA, 0x0D, 0x31, 0xC6, 0xFD]
+        );
+    }
+
+    #[test]
+    fn escapes_end_tag_checksum() {
+        let command = Command::Command(ControllerState::OK);
+        assert_eq!(
+            wire(&command, 0x0159),
+            [0xFA, 0x01, 0xA0, 0x04, 0x01, 0x59, 0xFE, 0xFD, 0xFD]
+        );
+    }
+
+    #[test]
+    fn escapes_escape() {
+        let command = Command::Command(ReportHeight::new(Height::from_mm(0x02FE)));
+        assert_eq!(
+            wire(&command, 0x09B3),
+            [0xFA, 0x03, 0x00, 0x01, 0x02, 0xFE, 0xFE, 0x09, 0xB3, 0x44, 0xFD]
+        );
+        let command = Command::Command(ControllerState::OK);
+        assert_eq!(
+            wire(&command, 0x015A),
+            [0xFA, 0x01, 0xA0, 0x04, 0x01, 0x5A, 0xFE, 0xFE, 0xFD]
+        );
+    }
+
+    /// Writes more than fits in a packet
+    struct TooLong;
+    impl Writeable for TooLong {
+        fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+            writer.write_all(&[0x42; MAX_PACKET_LEN])
+        }
+    }
+
+    #[test]
+    fn packet_too_long() {
+        assert!(matches!(
+            encode_packet(&TooLong, 1),
+            Err(ProtocolError::PacketTooLong(_))
+        ));
+        let mut wire = Wire(Vec::new());
+        assert!(matches!(
+            encode(&TooLong, 1, &mut wire),
+            Err(IoError::Protocol(ProtocolError::PacketTooLong(_)))
+        ));
+        assert!(wire.0.is_empty());
+    }
+}
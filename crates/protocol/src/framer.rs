@@ -0,0 +1,416 @@
+//! Splits the raw uart byte stream into packets
+//!
+//! Bytes are pushed in one at a time as they arrive from the uart so this can be driven directly
+//! from an interrupt or dma callback without any allocation.
+//!
+//! Start tags, end tags and escape bytes inside a packet are escaped on the wire, but line noise or
+//! a dropped byte can still leave a bare tag where it doesn't belong. So every bare start tag is
+//! kept as a place the packet might really start, and an end tag only ends the packet once one of
+//! those places gives a packet with the expected length for its command and a valid checksum.
+//!
+//! The lengths and checksum come from a [`DeskProfile`], the captured desk's unless the framer is
+//! made with [`Framer::with_profile`].
+
+use crate::{
+    new_protocol::{
+        Packet, PacketBytes, ProtocolError, ProtocolResult, END_TAG, ESCAPE, MAX_PACKET_LEN,
+        MIN_PACKET_LEN, START_TAG,
+    },
+    profile::DeskProfile,
+};
+
+/// How many bare start tags can be waiting for an end tag at once
+const MAX_STARTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FramerState {
+    /// Waiting for a start tag. Anything else is garbage and is dropped
+    Idle,
+    InPacket,
+    /// The previous byte was an escape so the next byte is data no matter what it is
+    Escaped,
+}
+
+enum Candidate {
+    /// `known` is whether the prefix is one of the profile's, so the length was checked as well
+    Valid {
+        known: bool,
+    },
+    /// The end tag can't be the end of this packet yet so it must have been data
+    Incomplete,
+    Invalid(ProtocolError),
+}
+
+/// Reassembles packets from a byte stream into a fixed buffer of `N` bytes
+///
+/// The packets that are produced include the start and end tags and have been unescaped.
+#[derive(Debug, Clone)]
+pub struct Framer<const N: usize = MAX_PACKET_LEN> {
+    buf: [u8; N],
+    len: usize,
+    state: FramerState,
+    /// Offsets into `buf` of the bare start tags in the current packet, oldest first
+    starts: [usize; MAX_STARTS],
+    /// How many bytes of the wire came before each of `starts`, escapes included
+    wire_starts: [usize; MAX_STARTS],
+    start_count: usize,
+    /// The bytes pushed into the current packet, escapes included
+    wire_len: usize,
+    last_wire_len: usize,
+    discarded: usize,
+    profile: &'static DeskProfile,
+}
+impl<const N: usize> Default for Framer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<const N: usize> Framer<N> {
+    pub const fn new() -> Self {
+        Self::with_profile(&DeskProfile::CAPTURED)
+    }
+
+    pub const fn with_profile(profile: &'static DeskProfile) -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            state: FramerState::Idle,
+            starts: [0; MAX_STARTS],
+            wire_starts: [0; MAX_STARTS],
+            start_count: 0,
+            wire_len: 0,
+            last_wire_len: 0,
+            discarded: 0,
+            profile,
+        }
+    }
+
+    /// Drops any partial packet and waits for the next start tag
+    ///
+    /// Call this when the uart reports a parity or framing error.
+    pub fn reset(&mut self) {
+        self.discarded += self.len;
+        self.clear();
+    }
+
+    /// The total number of bytes that have been dropped while looking for packets
+    ///
+    /// This counts unescaped bytes so an escaped byte and its escape count as one.
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+
+    /// How many bytes the last packet took on the wire, escapes included
+    ///
+    /// The packet was the last this many bytes pushed, so anything pushed before them since the
+    /// previous packet was dropped.
+    pub fn last_wire_len(&self) -> usize {
+        self.last_wire_len
+    }
+
+    /// Pushes the next byte off the wire. Returns the packet once its end tag has been pushed
+    ///
+    /// An error means a partial packet was dropped. The framer has already resynchronised and the
+    /// next byte can be pushed straight away.
+    pub fn push(&mut self, byte: u8) -> ProtocolResult<Option<Packet<'_>>> {
+        match (self.state, byte) {
+            (FramerState::Idle, START_TAG) | (FramerState::InPacket, START_TAG) => {
+                // In the middle of a packet either the end tag of the last packet was lost or this
+                // is data that should have been escaped. Which one is decided at the next end tag
+                self.add_start();
+                self.wire_len += 1;
+                self.append(byte)?;
+            }
+            (FramerState::Idle, _) => self.discarded += 1,
+            (FramerState::InPacket, ESCAPE) => {
+                self.wire_len += 1;
+                self.state = FramerState::Escaped;
+            }
+            (FramerState::InPacket, END_TAG) => {
+                self.wire_len += 1;
+                self.append(byte)?;
+                return self.end_packet();
+            }
+            (FramerState::InPacket, _) | (FramerState::Escaped, _) => {
+                self.wire_len += 1;
+                self.append(byte)?;
+            }
+        }
+        Ok(None)
+    }
+
+    fn packet(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.start_count = 0;
+        self.wire_len = 0;
+        self.state = FramerState::Idle;
+    }
+
+    fn add_start(&mut self) {
+        if self.start_count == MAX_STARTS {
+            self.drop_oldest_start();
+        }
+        self.starts[self.start_count] = self.len;
+        self.wire_starts[self.start_count] = self.wire_len;
+        self.start_count += 1;
+    }
+
+    /// Gives up on the oldest start tag and moves everything from the next one to the front
+    fn drop_oldest_start(&mut self) {
+        let shift = self.starts[1];
+        let wire_shift = self.wire_starts[1];
+        self.buf.copy_within(shift..self.len, 0);
+        self.len -= shift;
+        self.wire_len -= wire_shift;
+        self.discarded += shift;
+        for index in 1..self.start_count {
+            self.starts[index - 1] = self.starts[index] - shift;
+            self.wire_starts[index - 1] = self.wire_starts[index] - wire_shift;
+        }
+        self.start_count -= 1;
+    }
+
+    fn append(&mut self, byte: u8) -> ProtocolResult<()> {
+        if self.len == N {
+            if self.start_count > 1 {
+                self.drop_oldest_start();
+            } else {
+                // Overrun. Nothing this long is a real packet so wait for the next start tag
+                let error = ProtocolError::PacketTooLong(PacketBytes::new(self.packet()));
+                self.reset();
+                return Err(error);
+            }
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        self.state = FramerState::InPacket;
+        Ok(())
+    }
+
+    /// Called after an end tag. Picks the start tag that gives a valid packet if there is one
+    fn end_packet(&mut self) -> ProtocolResult<Option<Packet<'_>>> {
+        let mut incomplete = false;
+        let mut error = None;
+        let mut found = None;
+        for (index, &start) in self.starts[..self.start_count].iter().enumerate() {
+            match self.check_candidate(&self.buf[start..self.len]) {
+                Candidate::Valid { known: true } => {
+                    found = Some(index);
+                    break;
+                }
+                // Noise in front of a packet can xor to nothing, so a known command starting later
+                // is more likely than an unknown one that only has a valid checksum
+                Candidate::Valid { known: false } => found = found.or(Some(index)),
+                Candidate::Incomplete => incomplete = true,
+                Candidate::Invalid(candidate_error) => error = Some(candidate_error),
+            }
+        }
+
+        if let Some(index) = found {
+            let (start, len) = (self.starts[index], self.len);
+            self.last_wire_len = self.wire_len - self.wire_starts[index];
+            self.discarded += start;
+            self.clear();
+            return Packet::new(&self.buf[start..len]).map(Some);
+        }
+        if incomplete {
+            return Ok(None);
+        }
+        self.reset();
+        match error {
+            Some(error) => Err(error),
+            None => Ok(None),
+        }
+    }
+
+    fn check_candidate(&self, bytes: &[u8]) -> Candidate {
+        let packet = match Packet::new(bytes) {
+            Ok(packet) => packet,
+            Err(ProtocolError::PacketTooShort(_)) => return Candidate::Incomplete,
+            Err(error) => return Candidate::Invalid(error),
+        };
+        let expected_len = self
+            .profile
+            .expected_payload_len(packet.get_command_prefix());
+        match expected_len {
+            Some(payload_len) if bytes.len() < MIN_PACKET_LEN + payload_len => {
+                return Candidate::Incomplete
+            }
+            Some(payload_len) if bytes.len() > MIN_PACKET_LEN + payload_len => {
+                return Candidate::Invalid(ProtocolError::UnexpectedPayloadLength {
+                    expected: payload_len,
+                    actual: packet.get_data().len(),
+                    packet: PacketBytes::new(bytes),
+                })
+            }
+            _ => {}
+        }
+        match self.profile.verify_checksum(&packet) {
+            Ok(()) => Candidate::Valid {
+                known: expected_len.is_some(),
+            },
+            // Without a known length the end tag might still be data
+            Err(_) if expected_len.is_none() && bytes.len() < N => Candidate::Incomplete,
+            Err(error) => Candidate::Invalid(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const KEY_PRESS: &[u8] = &[0xFA, 0x17, 0x03, 0x01, 0x06, 0xCF, 0xDC, 0xFD];
+    const CONNECT: &[u8] = &[0xFA, 0x11, 0x01, 0x01, 0x3B, 0x2A, 0xFD];
+
+    /// Pushes every byte, giving the packets and errors in the order they came out
+    fn push_all(framer: &mut Framer, bytes: &[u8]) -> Vec<ProtocolResult<Vec<u8>>> {
+        bytes
+            .iter()
+            .filter_map(|&byte| match framer.push(byte) {
+                Ok(Some(packet)) => Some(Ok(packet.as_bytes().to_vec())),
+                Ok(None) => None,
+                Err(error) => Some(Err(error)),
+            })
+            .collect()
+    }
+
+    fn packets(framer: &mut Framer, bytes: &[u8]) -> Vec<Vec<u8>> {
+        push_all(framer, bytes)
+            .into_iter()
+            .map(|packet| packet.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn frames_back_to_back_packets() {
+        let mut framer = Framer::new();
+        let bytes = [KEY_PRESS, CONNECT].concat();
+        assert_eq!(packets(&mut framer, &bytes), [KEY_PRESS, CONNECT]);
+        assert_eq!(framer.discarded(), 0);
+    }
+
+    #[test]
+    fn unescapes() {
+        let mut framer = Framer::new();
+        let wire = [
+            0xFA, 0x03, 0x00, 0x01, 0x02, 0xFE, 0xFA, 0x0D, 0x31, 0xC6, 0xFD,
+        ];
+        let packet = [0xFA, 0x03, 0x00, 0x01, 0x02, 0xFA, 0x0D, 0x31, 0xC6, 0xFD];
+        assert_eq!(packets(&mut framer, &wire), [packet]);
+        assert_eq!(framer.last_wire_len(), wire.len());
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let mut framer = Framer::new();
+        let bytes = [&[0x12, 0x34, 0xFD, 0xFE, 0x00], KEY_PRESS].concat();
+        assert_eq!(packets(&mut framer, &bytes), [KEY_PRESS]);
+        assert_eq!(framer.discarded(), 5);
+    }
+
+    #[test]
+    fn overrun() {
+        let mut framer = Framer::new();
+        let bytes = [&[0xFA], &[0x42; MAX_PACKET_LEN][..], CONNECT].concat();
+        let results = push_all(&mut framer, &bytes);
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0], Err(ProtocolError::PacketTooLong(_))));
+        assert_eq!(results[1].as_deref().unwrap(), CONNECT);
+        assert_eq!(framer.discarded(), MAX_PACKET_LEN);
+    }
+
+    #[test]
+    fn missing_end_tag() {
+        // The first packet's end tag was lost, so the next start tag begins the packet
+        let mut framer = Framer::new();
+        let bytes = [&KEY_PRESS[..KEY_PRESS.len() - 1], CONNECT].concat();
+        assert_eq!(packets(&mut framer, &bytes), [CONNECT]);
+        assert_eq!(framer.discarded(), KEY_PRESS.len() - 1);
+    }
+
+    #[test]
+    fn bad_checksum_is_dropped() {
+        let mut framer = Framer::new();
+        let mut bad = KEY_PRESS.to_vec();
+        bad[6] ^= 1;
+        let results = push_all(&mut framer, &[&bad[..], CONNECT].concat());
+        assert!(matches!(results[0], Err(ProtocolError::BadCheckSum { .. })));
+        assert_eq!(results[1].as_deref().unwrap(), CONNECT);
+        assert_eq!(framer.discarded(), KEY_PRESS.len());
+    }
+
+    #[test]
+    fn reset_drops_partial_packet() {
+        let mut framer = Framer::new();
+        assert!(packets(&mut framer, &KEY_PRESS[..4]).is_empty());
+        framer.reset();
+        assert_eq!(packets(&mut framer, &KEY_PRESS[4..]), Vec::<Vec<u8>>::new());
+        assert_eq!(packets(&mut framer, CONNECT), [CONNECT]);
+        assert_eq!(framer.discarded(), KEY_PRESS.len());
+    }
+
+    #[test]
+    fn bare_start_tag_in_payload() {
+        // 0x02FA with the escape lost
+        let mut framer = Framer::new();
+        let packet = [0xFA, 0x03, 0x00, 0x01, 0x02, 0xFA, 0x0D, 0x31, 0xC6, 0xFD];
+        assert_eq!(packets(&mut framer, &packet), [packet]);
+        assert_eq!(framer.discarded(), 0);
+        assert_eq!(framer.last_wire_len(), packet.len());
+    }
+
+    #[test]
+    fn bare_end_tag_in_payload() {
+        // A heartbeat whose checksum is 0xFD, with the escape lost. The first end tag is too early
+        // for a heartbeat so it must be data
+        let mut framer = Framer::new();
+        let wire = [0xFA, 0x01, 0xA0, 0x04, 0x01, 0x59, 0xFD, 0xFD];
+        assert_eq!(packets(&mut framer, &wire), [wire]);
+    }
+
+    #[test]
+    fn drops_oldest_start() {
+        let mut framer = Framer::new();
+        let bytes = [&[0xFA; MAX_STARTS + 2][..], &CONNECT[1..]].concat();
+        assert_eq!(packets(&mut framer, &bytes), [CONNECT]);
+        assert_eq!(framer.discarded(), MAX_STARTS + 1);
+        assert_eq!(framer.last_wire_len(), CONNECT.len());
+    }
+
+    #[test]
+    fn capture_noise() {
+        // The start of `connect/desk.csv`, before the desk had finished booting
+        let mut framer = Framer::new();
+        let bytes = [
+            0x00, 0x01, 0x00, 0x00, 0xFF, 0xFA, 0x12, 0x01, 0x01, 0x17, 0x93, 0x96, 0xFD,
+        ];
+        assert_eq!(packets(&mut framer, &bytes), [&bytes[5..]]);
+        assert_eq!(framer.discarded(), 5);
+        assert_eq!(framer.last_wire_len(), bytes.len() - 5);
+    }
+
+    #[test]
+    fn counts_escaped_bytes_once() {
+        let mut framer = Framer::new();
+        // Cut off after an escaped start tag, then a byte that can't be an end tag
+        let bytes = [0xFA, 0x03, 0x00, 0x01, 0x02, 0xFE, 0xFA];
+        assert!(packets(&mut framer, &bytes).is_empty());
+        framer.reset();
+        assert_eq!(framer.discarded(), 6);
+    }
+
+    #[test]
+    fn profile_lengths() {
+        // An unknown prefix ends at the first end tag with a valid checksum
+        let mut framer = Framer::new();
+        let unknown = [0xFA, 0x42, 0x00, 0xFD, 0x00, 0x01, 0xBE, 0xFD];
+        assert_eq!(packets(&mut framer, &unknown), [unknown]);
+    }
+}
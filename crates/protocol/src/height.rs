@@ -0,0 +1,191 @@
+//! Desk heights
+//!
+//! The desk sends heights as a big endian u16 in tenths of a centimetre (0x02D4 is 72.4cm), which
+//! is the same as millimetres. Heights are kept in that unit so nothing is lost going to and from
+//! the wire, and floats are only used at the edges for display and user input.
+
+use crate::new_protocol::{PayloadField, ProtocolError, ProtocolResult, Write, Writeable};
+
+const MM_PER_INCH: f32 = 25.4;
+
+/// A height in tenths of a centimetre
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Height(u16);
+impl Height {
+    pub const fn from_mm(mm: u16) -> Self {
+        Self(mm)
+    }
+
+    /// Rounds to the nearest tenth of a centimetre
+    pub fn from_cm(cm: f32) -> ProtocolResult<Self> {
+        Self::round_mm(cm * 10.).ok_or(ProtocolError::InvalidHeight(cm))
+    }
+
+    /// Rounds to the nearest tenth of a centimetre
+    pub fn from_inches(inches: f32) -> ProtocolResult<Self> {
+        Self::round_mm(inches * MM_PER_INCH).ok_or(ProtocolError::InvalidHeight(inches))
+    }
+
+    fn round_mm(mm: f32) -> Option<Self> {
+        // Written so NaN fails as well
+        if !(mm >= 0. && mm + 0.5 < u16::MAX as f32 + 1.) {
+            return None;
+        }
+        // f32::round isn't in core. This is the same for positive numbers
+        Some(Self((mm + 0.5) as u16))
+    }
+
+    pub const fn to_mm(self) -> u16 {
+        self.0
+    }
+
+    pub fn to_cm(self) -> f32 {
+        self.0 as f32 / 10.
+    }
+
+    pub fn to_inches(self) -> f32 {
+        self.0 as f32 / MM_PER_INCH
+    }
+
+    pub const fn abs_diff(self, other: Self) -> Self {
+        Self(self.0.abs_diff(other.0))
+    }
+}
+impl core::fmt::Display for Height {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}cm", self.0 / 10, self.0 % 10)
+    }
+}
+impl Writeable for Height {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        self.0.write_to(writer)
+    }
+}
+impl PayloadField for Height {
+    const LEN: usize = u16::LEN;
+
+    fn read(bytes: &[u8]) -> ProtocolResult<Self> {
+        u16::read(bytes).map(Self)
+    }
+}
+
+/// The lowest and highest heights the desk will move to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeightLimits {
+    pub min: Height,
+    pub max: Height,
+}
+impl Default for HeightLimits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+impl HeightLimits {
+    /// What the captured desk reports in its 0x21 and 0x22 registers
+    pub const DEFAULT: Self = Self {
+        min: Height::from_mm(650),
+        max: Height::from_mm(1250),
+    };
+
+    pub fn contains(&self, height: Height) -> bool {
+        (self.min..=self.max).contains(&height)
+    }
+
+    pub fn check(&self, height: Height) -> ProtocolResult<Height> {
+        if self.contains(height) {
+            Ok(height)
+        } else {
+            Err(ProtocolError::HeightOutOfRange {
+                height,
+                min: self.min,
+                max: self.max,
+            })
+        }
+    }
+
+    pub fn clamp(&self, height: Height) -> Height {
+        height.clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn centimetres() {
+        assert_eq!(Height::from_cm(72.4).unwrap(), Height::from_mm(724));
+        // Rounds to the nearest millimetre
+        assert_eq!(Height::from_cm(72.44).unwrap(), Height::from_mm(724));
+        assert_eq!(Height::from_cm(72.46).unwrap(), Height::from_mm(725));
+        assert_eq!(Height::from_cm(0.).unwrap(), Height::from_mm(0));
+        assert_eq!(Height::from_cm(6553.5).unwrap(), Height::from_mm(u16::MAX));
+        assert_eq!(Height::from_mm(724).to_cm(), 72.4);
+    }
+
+    #[test]
+    fn inches() {
+        assert_eq!(Height::from_inches(1.).unwrap(), Height::from_mm(25));
+        // 723.9mm
+        assert_eq!(Height::from_inches(28.5).unwrap(), Height::from_mm(724));
+        // 0.508mm rounds up, 0.254mm rounds down
+        assert_eq!(Height::from_inches(0.02).unwrap(), Height::from_mm(1));
+        assert_eq!(Height::from_inches(0.01).unwrap(), Height::from_mm(0));
+        assert_eq!(Height::from_mm(254).to_inches(), 10.);
+    }
+
+    #[test]
+    fn invalid() {
+        for cm in [-0.1, 6553.6, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                Height::from_cm(cm),
+                Err(ProtocolError::InvalidHeight(_))
+            ));
+        }
+        for inches in [-1., 2600., f32::NAN] {
+            assert!(matches!(
+                Height::from_inches(inches),
+                Err(ProtocolError::InvalidHeight(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(std::format!("{}", Height::from_mm(724)), "72.4cm");
+        assert_eq!(std::format!("{}", Height::from_mm(1250)), "125.0cm");
+        assert_eq!(std::format!("{}", Height::from_mm(5)), "0.5cm");
+    }
+
+    #[test]
+    fn abs_diff() {
+        let low = Height::from_mm(650);
+        let high = Height::from_mm(724);
+        assert_eq!(low.abs_diff(high), Height::from_mm(74));
+        assert_eq!(high.abs_diff(low), Height::from_mm(74));
+    }
+
+    #[test]
+    fn limits() {
+        let limits = HeightLimits::DEFAULT;
+        let below = Height::from_mm(649);
+        let above = Height::from_mm(1251);
+        assert!(limits.contains(limits.min));
+        assert!(limits.contains(limits.max));
+        assert!(!limits.contains(below));
+        assert!(!limits.contains(above));
+
+        assert_eq!(limits.clamp(below), limits.min);
+        assert_eq!(limits.clamp(limits.min), limits.min);
+        assert_eq!(limits.clamp(Height::from_mm(724)), Height::from_mm(724));
+        assert_eq!(limits.clamp(limits.max), limits.max);
+        assert_eq!(limits.clamp(above), limits.max);
+
+        assert_eq!(limits.check(limits.max).unwrap(), limits.max);
+        assert!(matches!(
+            limits.check(above),
+            Err(ProtocolError::HeightOutOfRange { height, min, max })
+                if height == above && min == limits.min && max == limits.max
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+//! Adapters for [`embedded_io`] so the protocol can sit directly on a HAL's uart
+//!
+//! [`IoWriter`] lets anything that implements [`embedded_io::Write`] be passed to
+//! [`encode`](crate::encoder::encode), and [`read_packet`] pulls bytes from an
+//! [`embedded_io::Read`] through a [`Framer`] until a whole packet has arrived.
+
+use embedded_io::{Error, ErrorKind, Read};
+
+use crate::{
+    framer::Framer,
+    new_protocol::{IoError, PacketBuf, ProtocolError, Write},
+};
+
+/// Writes packets to an [`embedded_io::Write`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoWriter<T>(pub T);
+impl<T> IoWriter<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+impl<T: embedded_io::Write> Write for IoWriter<T> {
+    type Error = T::Error;
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), T::Error> {
+        self.0.write_all(buf)
+    }
+}
+
+/// Reads until `framer` has a whole packet
+///
+/// A [`IoError::Protocol`] means a partial packet was dropped. The framer has already
+/// resynchronised so this can be called again straight away.
+pub fn read_packet<R: Read, const N: usize>(
+    reader: &mut R,
+    framer: &mut Framer<N>,
+) -> Result<PacketBuf<N>, IoError<R::Error>> {
+    let mut byte = [0];
+    loop {
+        if reader.read(&mut byte).map_err(IoError::Io)? == 0 {
+            return Err(IoError::Eof);
+        }
+        if let Some(packet) = framer.push(byte[0])? {
+            return Ok(PacketBuf::try_from(packet)?);
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidData
+    }
+}
+impl<E: Error> Error for IoError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            IoError::Protocol(error) => error.kind(),
+            IoError::Io(error) => error.kind(),
+            IoError::Eof => ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoder::encode,
+        height::Height,
+        new_protocol::{
+            BaseCommand, ChangeHeight, ChangeHeightState, Command, ReportHeight, MAX_PACKET_LEN,
+        },
+    };
+    use embedded_io::SliceWriteError;
+
+    const KEY_PRESS: [u8; 8] = [0xFA, 0x17, 0x03, 0x01, 0x06, 0xCF, 0xDC, 0xFD];
+    /// A height report of 0x02FE, which has to be escaped
+    const ESCAPED: [u8; 11] = [
+        0xFA, 0x03, 0x00, 0x01, 0x02, 0xFE, 0xFA, 0x0D, 0x31, 0xC6, 0xFD,
+    ];
+
+    fn framer() -> Framer<MAX_PACKET_LEN> {
+        Framer::new()
+    }
+
+    #[test]
+    fn reads_after_noise() {
+        let bytes = [&[0x00, 0x13, 0xFD][..], &KEY_PRESS, &ESCAPED].concat();
+        let mut reader = &bytes[..];
+        let mut framer = framer();
+        let packet = read_packet(&mut reader, &mut framer).unwrap();
+        assert_eq!(packet.as_bytes(), KEY_PRESS);
+        assert_eq!(framer.discarded(), 3);
+
+        let packet = read_packet(&mut reader, &mut framer).unwrap();
+        let command = BaseCommand::decode(&packet.as_packet()).unwrap();
+        assert!(matches!(
+            command,
+            BaseCommand::ReportHeight(Command::Command(report))
+                if report.height == Height::from_mm(0x02FA)
+        ));
+        assert!(matches!(
+            read_packet(&mut reader, &mut framer),
+            Err(IoError::Eof)
+        ));
+    }
+
+    #[test]
+    fn eof_partway_through_a_packet() {
+        let mut reader = &KEY_PRESS[..4];
+        let mut framer = framer();
+        assert!(matches!(
+            read_packet(&mut reader, &mut framer),
+            Err(IoError::Eof)
+        ));
+        // The rest arriving later, such as from the next read of a uart, finishes the packet
+        let mut reader = &KEY_PRESS[4..];
+        let packet = read_packet(&mut reader, &mut framer).unwrap();
+        assert_eq!(packet.as_bytes(), KEY_PRESS);
+    }
+
+    #[test]
+    fn resynchronises_after_an_error() {
+        let bytes = [&[0xFA][..], &[0x42; MAX_PACKET_LEN], &KEY_PRESS].concat();
+        let mut reader = &bytes[..];
+        let mut framer = framer();
+        let error = read_packet(&mut reader, &mut framer).unwrap_err();
+        assert!(matches!(
+            error,
+            IoError::Protocol(ProtocolError::PacketTooLong(_))
+        ));
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        let packet = read_packet(&mut reader, &mut framer).unwrap();
+        assert_eq!(packet.as_bytes(), KEY_PRESS);
+    }
+
+    #[test]
+    fn writes_escaped_packets() {
+        let report =
+            BaseCommand::ReportHeight(Command::Command(ReportHeight::new(Height::from_mm(0x02FA))));
+        let mut buf = [0; 16];
+        let mut writer = IoWriter(&mut buf[..]);
+        encode(&report, 0x0D31, &mut writer).unwrap();
+        let unused = writer.into_inner().len();
+        assert_eq!(buf[..buf.len() - unused], ESCAPED);
+    }
+
+    #[test]
+    fn write_errors_are_passed_on() {
+        let mut buf = [0; 4];
+        let mut writer = IoWriter(&mut buf[..]);
+        let key = Command::Command(ChangeHeight::Up(ChangeHeightState::Start));
+        let error = encode(&key, 1, &mut writer).unwrap_err();
+        assert!(matches!(error, IoError::Io(SliceWriteError::Full)));
+        // What fitted was written
+        assert_eq!(buf, KEY_PRESS[..4]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Keeps track of the physical keypad from the packets it sends
+//!
+//! The keypad sends a [`ControllerState`] heartbeat about every 200ms whether or not a key is held,
+//! and a [`ChangeHeight`] packet with [`ChangeHeightState::Start`] when a key is pressed and
+//! [`ChangeHeightState::Stop`] when it is released. Times are milliseconds from any monotonic
+//! clock.
+
+use crate::new_protocol::{BaseCommand, ChangeHeight, ChangeHeightState, Command, ControllerState};
+
+/// How long without a heartbeat before the keypad is treated as gone. About five heartbeats
+pub const HEARTBEAT_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeypadState {
+    /// No heartbeat has been seen within the timeout
+    Absent,
+    Idle,
+    /// A key is held down. The key is the [`ChangeHeight`] command it sent
+    Held(ChangeHeight<()>),
+}
+
+#[derive(Debug, Clone)]
+pub struct KeypadMonitor {
+    timeout_ms: u64,
+    last_heartbeat_ms: Option<u64>,
+    status: Option<ControllerState>,
+    held: Option<ChangeHeight<()>>,
+}
+impl Default for KeypadMonitor {
+    fn default() -> Self {
+        Self::new(HEARTBEAT_TIMEOUT_MS)
+    }
+}
+impl KeypadMonitor {
+    pub const fn new(timeout_ms: u64) -> Self {
+        Self {
+            timeout_ms,
+            last_heartbeat_ms: None,
+            status: None,
+            held: None,
+        }
+    }
+
+    /// Updates the keypad state from a packet the keypad sent. Everything else is ignored
+    pub fn observe(&mut self, command: &BaseCommand, now_ms: u64) {
+        match command {
+            BaseCommand::ReportControllerState(Command::Command(status)) => {
+                self.last_heartbeat_ms = Some(now_ms);
+                self.status = Some(*status);
+            }
+            BaseCommand::ChangeHeight(Command::Command(change_height)) => {
+                match change_height.state() {
+                    ChangeHeightState::Start => self.held = Some(change_height.with_state(())),
+                    // Only the key that is held can be released
+                    ChangeHeightState::Stop if self.held == Some(change_height.with_state(())) => {
+                        self.held = None
+                    }
+                    ChangeHeightState::Stop => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn state(&self, now_ms: u64) -> KeypadState {
+        if !self.is_present(now_ms) {
+            return KeypadState::Absent;
+        }
+        match self.held {
+            Some(key) => KeypadState::Held(key),
+            None => KeypadState::Idle,
+        }
+    }
+
+    pub fn is_present(&self, now_ms: u64) -> bool {
+        self.last_heartbeat_ms
+            .is_some_and(|last| now_ms.saturating_sub(last) <= self.timeout_ms)
+    }
+
+    /// The status from the last heartbeat, including any bits that aren't understood yet
+    pub fn status(&self) -> Option<ControllerState> {
+        self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_protocol::{ChangeHeightResponse, ResponseState};
+
+    const HEARTBEAT: BaseCommand =
+        BaseCommand::ReportControllerState(Command::Command(ControllerState::OK));
+
+    fn key(key: ChangeHeight<()>, state: ChangeHeightState) -> BaseCommand {
+        BaseCommand::ChangeHeight(Command::Command(key.with_state(state)))
+    }
+
+    #[test]
+    fn absent_until_the_first_heartbeat() {
+        let mut keypad = KeypadMonitor::default();
+        assert_eq!(keypad.state(0), KeypadState::Absent);
+        assert_eq!(keypad.status(), None);
+        // A key press alone doesn't mean the keypad is there
+        keypad.observe(&key(ChangeHeight::Up(()), ChangeHeightState::Start), 0);
+        assert_eq!(keypad.state(0), KeypadState::Absent);
+
+        keypad.observe(&HEARTBEAT, 10);
+        assert_eq!(keypad.state(10), KeypadState::Held(ChangeHeight::Up(())));
+        assert_eq!(keypad.status(), Some(ControllerState::OK));
+    }
+
+    #[test]
+    fn presence_timeout() {
+        let mut keypad = KeypadMonitor::new(500);
+        keypad.observe(&HEARTBEAT, 1000);
+        assert!(keypad.is_present(1000));
+        assert!(keypad.is_present(1500));
+        assert!(!keypad.is_present(1501));
+        assert_eq!(keypad.state(1501), KeypadState::Absent);
+        // A clock that reads earlier than the heartbeat doesn't underflow
+        assert!(keypad.is_present(0));
+    }
+
+    #[test]
+    fn missed_heartbeats() {
+        let mut keypad = KeypadMonitor::default();
+        keypad.observe(&HEARTBEAT, 0);
+        // Four heartbeats every 200ms are lost, the keypad is still there
+        assert_eq!(keypad.state(HEARTBEAT_TIMEOUT_MS), KeypadState::Idle);
+        // The fifth as well, it has gone
+        assert_eq!(
+            keypad.state(HEARTBEAT_TIMEOUT_MS + 200),
+            KeypadState::Absent
+        );
+        // And comes back with the next heartbeat
+        keypad.observe(&HEARTBEAT, HEARTBEAT_TIMEOUT_MS + 400);
+        assert_eq!(keypad.state(HEARTBEAT_TIMEOUT_MS + 400), KeypadState::Idle);
+    }
+
+    #[test]
+    fn held_and_released() {
+        let mut keypad = KeypadMonitor::default();
+        keypad.observe(&HEARTBEAT, 0);
+        assert_eq!(keypad.state(0), KeypadState::Idle);
+        keypad.observe(&key(ChangeHeight::Down(()), ChangeHeightState::Start), 50);
+        assert_eq!(keypad.state(50), KeypadState::Held(ChangeHeight::Down(())));
+        // Heartbeats don't change which key is held
+        keypad.observe(&HEARTBEAT, 200);
+        assert_eq!(keypad.state(200), KeypadState::Held(ChangeHeight::Down(())));
+        keypad.observe(&key(ChangeHeight::Down(()), ChangeHeightState::Stop), 300);
+        assert_eq!(keypad.state(300), KeypadState::Idle);
+    }
+
+    #[test]
+    fn releasing_another_key() {
+        let mut keypad = KeypadMonitor::default();
+        keypad.observe(&HEARTBEAT, 0);
+        keypad.observe(&key(ChangeHeight::Up(()), ChangeHeightState::Start), 0);
+        keypad.observe(
+            &key(ChangeHeight::SavedOne(()), ChangeHeightState::Stop),
+            10,
+        );
+        assert_eq!(keypad.state(10), KeypadState::Held(ChangeHeight::Up(())));
+        // Pressing another key replaces the held one
+        keypad.observe(
+            &key(ChangeHeight::SavedTwo(()), ChangeHeightState::Start),
+            20,
+        );
+        assert_eq!(
+            keypad.state(20),
+            KeypadState::Held(ChangeHeight::SavedTwo(()))
+        );
+        keypad.observe(&key(ChangeHeight::Up(()), ChangeHeightState::Stop), 30);
+        assert_eq!(
+            keypad.state(30),
+            KeypadState::Held(ChangeHeight::SavedTwo(()))
+        );
+        keypad.observe(
+            &key(ChangeHeight::SavedTwo(()), ChangeHeightState::Stop),
+            40,
+        );
+        assert_eq!(keypad.state(40), KeypadState::Idle);
+    }
+
+    #[test]
+    fn ignores_the_desk() {
+        let mut keypad = KeypadMonitor::default();
+        keypad.observe(&HEARTBEAT, 0);
+        let ack =
+            BaseCommand::ChangeHeight(Command::Reponse(ChangeHeight::Up(ChangeHeightResponse {
+                state: ChangeHeightState::Start,
+                response: ResponseState::Ok,
+            })));
+        keypad.observe(&ack, 10);
+        assert_eq!(keypad.state(10), KeypadState::Idle);
+    }
+}
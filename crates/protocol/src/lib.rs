@@ -1,4 +1,26 @@
 #![no_std]
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+pub mod bus;
+pub mod config;
+pub mod encoder;
+pub mod framer;
+pub mod height;
+#[cfg(feature = "embedded-io")]
+pub mod io;
+pub mod keypad;
+#[cfg(feature = "embedded-io-async")]
+pub mod link;
+mod macros;
+pub mod motion;
 pub mod new_protocol;
+pub mod preset;
+pub mod profile;
 pub mod protocol;
+pub mod sequence;
+pub mod session;
+pub mod simulator;
+#[cfg(feature = "std")]
+pub mod std_io;
@@ -0,0 +1,420 @@
+//! An async driver for the keypad's end of the link, for firmware running on an async executor
+//!
+//! [`DeskLink`] owns the uart and does the framing, encoding and packet numbering. Sending never
+//! waits for the desk, and [`DeskLink::request`] keeps any events that arrive before the response
+//! so [`DeskLink::next_event`] still sees them. Nothing here has a timeout. Wrap calls in the
+//! executor's timeout (`embassy_time::with_timeout` for example) where the desk might not answer.
+//!
+//! With the `std` feature, [`pipe`] gives two connected in-memory ends so the link can be run
+//! against a [`DeskSimulator`] on a host.
+//!
+//! [`DeskSimulator`]: crate::simulator::DeskSimulator
+
+use embedded_io_async::{Read, Write as AsyncWrite};
+
+use crate::{
+    config::{DeskConfig, DeskConfigBuilder},
+    encoder::{encode_packet, write_escaped},
+    framer::Framer,
+    new_protocol::{
+        BaseCommand, Command, Connect, ConnectResponse, DeviceIdentity, EventResponse, IdentityAck,
+        IoError, PacketBuf, ProtocolResult, Register, RegisterValue, Write, Writeable,
+        MAX_ESCAPED_LEN, MAX_PACKET_LEN,
+    },
+    profile::DeskProfile,
+};
+
+/// How many events can arrive while waiting for a response before the oldest is dropped
+const MAX_PENDING: usize = 8;
+
+/// An escaped packet ready to be written
+struct WireBuf {
+    buf: [u8; MAX_ESCAPED_LEN],
+    len: usize,
+}
+impl Write for WireBuf {
+    type Error = core::convert::Infallible;
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        // Can't overflow, the packet was built before it was escaped
+        self.buf[self.len..self.len + buf.len()].copy_from_slice(buf);
+        self.len += buf.len();
+        Ok(())
+    }
+}
+
+pub struct DeskLink<T> {
+    uart: T,
+    framer: Framer<MAX_PACKET_LEN>,
+    profile: &'static DeskProfile,
+    /// The number of the next packet sent
+    packet_num: u16,
+    buf: [u8; MAX_PACKET_LEN],
+    pos: usize,
+    len: usize,
+    /// Events that arrived while waiting for a response, oldest first
+    pending: [Option<BaseCommand>; MAX_PENDING],
+}
+impl<T: Read + AsyncWrite> DeskLink<T> {
+    pub fn new(uart: T) -> Self {
+        Self::with_profile(uart, &DeskProfile::CAPTURED)
+    }
+
+    pub fn with_profile(uart: T, profile: &'static DeskProfile) -> Self {
+        Self {
+            uart,
+            framer: Framer::with_profile(profile),
+            profile,
+            // The captured keypad counts from 1 when it connects
+            packet_num: 1,
+            buf: [0; MAX_PACKET_LEN],
+            pos: 0,
+            len: 0,
+            pending: [const { None }; MAX_PENDING],
+        }
+    }
+
+    /// Bytes dropped by the framer while looking for packets
+    pub fn discarded(&self) -> usize {
+        self.framer.discarded()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.uart
+    }
+
+    /// Sends `command` as the next packet and flushes the uart
+    pub async fn send(&mut self, command: &BaseCommand) -> Result<(), IoError<T::Error>> {
+        self.send_packet(command).await
+    }
+
+    /// The next packet from the desk, starting with any that arrived during a request
+    pub async fn next_event(&mut self) -> Result<BaseCommand, IoError<T::Error>> {
+        if let Some(command) = self.pending[0].take() {
+            self.pending.rotate_left(1);
+            return Ok(command);
+        }
+        let packet = self.read_packet().await?;
+        Ok(BaseCommand::decode(&packet.as_packet())?)
+    }
+
+    /// Sends `event` and waits for the desk's response to it
+    ///
+    /// The response has to have the same command id as the event. Anything else that arrives
+    /// first is kept for [`DeskLink::next_event`], and packets that can't be framed or decoded are
+    /// dropped. Only use this for events the desk answers: it never answers a
+    /// [`ReportHeight`](crate::new_protocol::ReportHeight) or the release of a preset key.
+    pub async fn request<C: EventResponse>(
+        &mut self,
+        event: C,
+    ) -> Result<C::Response, IoError<T::Error>>
+    where
+        C::Response: Sized,
+    {
+        let command_id = event.command_id();
+        self.send_packet(&Command::Command(event)).await?;
+        loop {
+            // Not what is being waited for, so a bad packet is dropped rather than failing the
+            // request
+            let packet = match self.read_packet().await {
+                Ok(packet) => packet,
+                Err(IoError::Protocol(_)) => continue,
+                Err(error) => return Err(error),
+            };
+            let packet = packet.as_packet();
+            if packet.get_command_prefix() == C::RESPONSE_ID
+                && packet.get_command_id() == command_id
+            {
+                return Ok(C::read_response_from(&packet)?);
+            }
+            if let Ok(command) = BaseCommand::decode(&packet) {
+                self.queue(command);
+            }
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<ConnectResponse, IoError<T::Error>> {
+        self.request(Connect {}).await
+    }
+
+    pub async fn identify(
+        &mut self,
+        identity: DeviceIdentity,
+    ) -> Result<IdentityAck, IoError<T::Error>> {
+        self.request(identity).await
+    }
+
+    pub async fn read_register(
+        &mut self,
+        register: Register,
+    ) -> Result<RegisterValue, IoError<T::Error>> {
+        self.request(register).await
+    }
+
+    /// Reads every register, one at a time in the order the keypad asks
+    pub async fn read_config(&mut self) -> Result<DeskConfig, IoError<T::Error>> {
+        let mut builder = DeskConfigBuilder::new();
+        for register in Register::ALL {
+            builder.set(self.read_register(register).await?);
+        }
+        Ok(builder.build()?)
+    }
+
+    async fn send_packet<C: Writeable>(&mut self, command: &C) -> Result<(), IoError<T::Error>> {
+        let wire = self.encode(command)?;
+        self.packet_num = self.packet_num.wrapping_add(1);
+        self.uart
+            .write_all(&wire.buf[..wire.len])
+            .await
+            .map_err(IoError::Io)?;
+        self.uart.flush().await.map_err(IoError::Io)
+    }
+
+    fn encode<C: Writeable>(&self, command: &C) -> ProtocolResult<WireBuf> {
+        let packet = encode_packet(command, self.packet_num)?;
+        let packet = self.profile.to_wire(&packet.as_packet())?;
+        let mut wire = WireBuf {
+            buf: [0; MAX_ESCAPED_LEN],
+            len: 0,
+        };
+        let Ok(()) = write_escaped(packet.as_bytes(), &mut wire);
+        Ok(wire)
+    }
+
+    /// Reads until the framer has a whole packet, translated to the captured desk's protocol
+    async fn read_packet(&mut self) -> Result<PacketBuf, IoError<T::Error>> {
+        loop {
+            if self.pos == self.len {
+                let len = self.uart.read(&mut self.buf).await.map_err(IoError::Io)?;
+                if len == 0 {
+                    return Err(IoError::Eof);
+                }
+                self.pos = 0;
+                self.len = len;
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            if let Some(packet) = self.framer.push(byte)? {
+                return Ok(self.profile.to_captured(&packet)?);
+            }
+        }
+    }
+
+    fn queue(&mut self, command: BaseCommand) {
+        if self.pending[MAX_PENDING - 1].is_some() {
+            // Full, the newest events matter more than the oldest
+            self.pending.rotate_left(1);
+            self.pending[MAX_PENDING - 1] = None;
+        }
+        if let Some(slot) = self.pending.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(command);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use pipe::{pipe, PipeEnd};
+
+#[cfg(feature = "std")]
+mod pipe {
+    use core::{convert::Infallible, future::poll_fn, task::Poll};
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        task::Waker,
+    };
+
+    use embedded_io_async::{ErrorType, Read, Write};
+
+    /// Bytes going one way
+    #[derive(Default)]
+    struct Channel {
+        bytes: VecDeque<u8>,
+        reader: Option<Waker>,
+        closed: bool,
+    }
+
+    /// One end of an in-memory byte pipe made by [`pipe`]
+    ///
+    /// Reads wait until the other end has written something, and return 0 once the other end has
+    /// been dropped and everything it wrote has been read. Writes never wait.
+    pub struct PipeEnd {
+        rx: Arc<Mutex<Channel>>,
+        tx: Arc<Mutex<Channel>>,
+    }
+
+    /// Two connected ends. What one writes the other reads
+    pub fn pipe() -> (PipeEnd, PipeEnd) {
+        let a = Arc::new(Mutex::new(Channel::default()));
+        let b = Arc::new(Mutex::new(Channel::default()));
+        (
+            PipeEnd {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            PipeEnd { rx: b, tx: a },
+        )
+    }
+
+    impl PipeEnd {
+        /// Bytes written by the other end that haven't been read yet
+        pub fn available(&self) -> usize {
+            self.rx.lock().unwrap().bytes.len()
+        }
+    }
+    impl Drop for PipeEnd {
+        fn drop(&mut self) {
+            let mut tx = self.tx.lock().unwrap();
+            tx.closed = true;
+            if let Some(waker) = tx.reader.take() {
+                waker.wake();
+            }
+        }
+    }
+    impl ErrorType for PipeEnd {
+        type Error = Infallible;
+    }
+    impl Read for PipeEnd {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            poll_fn(|cx| {
+                let mut rx = self.rx.lock().unwrap();
+                if rx.bytes.is_empty() && !rx.closed && !buf.is_empty() {
+                    rx.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                let len = buf.len().min(rx.bytes.len());
+                for (dest, byte) in buf.iter_mut().zip(rx.bytes.drain(..len)) {
+                    *dest = byte;
+                }
+                Poll::Ready(Ok(len))
+            })
+            .await
+        }
+    }
+    impl Write for PipeEnd {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            let mut tx = self.tx.lock().unwrap();
+            tx.bytes.extend(buf);
+            if let Some(waker) = tx.reader.take() {
+                waker.wake();
+            }
+            Ok(buf.len())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::vec::Vec;
+
+    use embassy_futures::{block_on, join::join};
+    use embedded_io_async::{Read, Write};
+
+    use super::*;
+    use crate::{
+        encoder::encode,
+        height::Height,
+        new_protocol::{
+            tests::{packet, Wire},
+            ProtocolError, ReportHeight,
+        },
+        simulator::DeskSimulator,
+    };
+
+    /// Plays the desk until the link is dropped. Time stands still, so the height is only
+    /// reported once
+    async fn run_desk(mut uart: PipeEnd, mut desk: DeskSimulator) -> DeskSimulator {
+        let mut framer = Framer::<MAX_PACKET_LEN>::new();
+        let mut buf = [0; 64];
+        let mut packet_num = 1;
+        loop {
+            let len = uart.read(&mut buf).await.unwrap();
+            if len == 0 {
+                return desk;
+            }
+            for &byte in &buf[..len] {
+                if let Some(packet) = framer.push(byte).unwrap() {
+                    desk.observe(&BaseCommand::decode(&packet).unwrap(), 0);
+                }
+            }
+            while let Some(command) = desk.poll(0) {
+                let mut wire = Wire(Vec::new());
+                encode(&command, packet_num, &mut wire).unwrap();
+                packet_num += 1;
+                uart.write_all(&wire.0).await.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_with_simulator() {
+        let (keypad, desk) = pipe();
+        let mut link = DeskLink::new(keypad);
+        let keypad = async {
+            assert!(link.connect().await.unwrap().connected);
+            let ack = link.identify(DeviceIdentity::KEYPAD).await.unwrap();
+            assert_eq!(ack, DeviceIdentity::KEYPAD.ack());
+            assert_eq!(link.read_config().await.unwrap(), DeskConfig::DEFAULT);
+
+            // The height report and the desk's identity arrived during the requests
+            assert!(matches!(
+                link.next_event().await.unwrap(),
+                BaseCommand::ReportHeight(Command::Command(report))
+                    if report == ReportHeight::new(Height::from_mm(724))
+            ));
+            assert!(matches!(
+                link.next_event().await.unwrap(),
+                BaseCommand::Identify(Command::Command(identity)) if identity == DeviceIdentity::DESK
+            ));
+            // Connect, identify and every register
+            assert_eq!(link.packet_num, 2 + Register::ALL.len() as u16 + 1);
+            drop(link);
+        };
+        let (_, desk) = block_on(join(keypad, run_desk(desk, DeskSimulator::default())));
+        assert!(desk.is_connected());
+    }
+
+    #[test]
+    fn request_drops_bad_packets() {
+        let (keypad, mut desk) = pipe();
+        let mut link = DeskLink::new(keypad);
+        block_on(async {
+            // Too long to be a packet
+            desk.write_all(&[0xFA; 1]).await.unwrap();
+            desk.write_all(&[0x17; MAX_PACKET_LEN]).await.unwrap();
+            desk.write_all(&[0xFD]).await.unwrap();
+            // A key ack for a key that doesn't exist
+            let mut wire = Wire(Vec::new());
+            let body = packet(&[0x18, 0x42, 0x01, 0x00], 1);
+            write_escaped(body.as_bytes(), &mut wire).unwrap();
+            let connected = Command::<Connect>::Reponse(ConnectResponse { connected: true });
+            encode(&connected, 2, &mut wire).unwrap();
+            desk.write_all(&wire.0).await.unwrap();
+
+            assert!(link.connect().await.unwrap().connected);
+        });
+        assert!(link.discarded() > 0);
+        assert!(link.pending.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn next_event_reports_bad_packets() {
+        let (keypad, mut desk) = pipe();
+        let mut link = DeskLink::new(keypad);
+        block_on(async {
+            let mut wire = Wire(Vec::new());
+            let body = packet(&[0x18, 0x42, 0x01, 0x00], 1);
+            write_escaped(body.as_bytes(), &mut wire).unwrap();
+            desk.write_all(&wire.0).await.unwrap();
+            drop(desk);
+
+            assert!(matches!(
+                link.next_event().await,
+                Err(IoError::Protocol(
+                    ProtocolError::UnrecognizedChangeHeightCommand { .. }
+                ))
+            ));
+            assert!(matches!(link.next_event().await, Err(IoError::Eof)));
+        });
+    }
+}
@@ -0,0 +1,397 @@
+//! Boilerplate for declaring commands and decoding them
+//!
+//! A command is an event and its response, sharing a command id and each with its own prefix.
+//! [`command!`] declares both structs and generates their [`CommandId`], [`EventResponse`],
+//! [`Writeable`] and [`Display`] impls, a `PAYLOAD_LEN` constant and a round trip test. Every
+//! field has to implement [`PayloadField`].
+//!
+//! ```ignore
+//! command! {
+//!     prefix: 0x11,
+//!     command_id: 0x01,
+//!     /// The keypad asking to start the link
+//!     event Connect {}
+//!     response ConnectResponse {
+//!         connected: bool,
+//!     }
+//! }
+//! ```
+//!
+//! `response_prefix: 0x..,` can follow the prefix when the response isn't the prefix plus one.
+//!
+//! [`base_command!`] declares [`BaseCommand`] from a list of variants, one per [`EventResponse`],
+//! and generates [`BaseCommand::decode`] and [`BaseCommand::expected_payload_len`] from them. A new
+//! command is decoded once it has a line there, `Connect(Connect),` for the command above.
+//!
+//! [`BaseCommand::decode`]: crate::new_protocol::BaseCommand::decode
+//! [`CommandId`]: crate::new_protocol::CommandId
+//! [`EventResponse`]: crate::new_protocol::EventResponse
+//! [`Writeable`]: crate::new_protocol::Writeable
+//! [`Display`]: core::fmt::Display
+//! [`BaseCommand`]: crate::new_protocol::BaseCommand
+//! [`BaseCommand::expected_payload_len`]: crate::new_protocol::BaseCommand::expected_payload_len
+//! [`PayloadField`]: crate::new_protocol::PayloadField
+
+macro_rules! command {
+    (
+        prefix: $event_id:literal,
+        $(response_prefix: $response_id:literal,)?
+        command_id: $command_id:literal,
+        $(#[$event_meta:meta])*
+        event $event:ident {
+            $($(#[$event_field_meta:meta])* $event_field:ident: $event_type:ty),* $(,)?
+        }
+        $(#[$response_meta:meta])*
+        response $response:ident {
+            $($(#[$response_field_meta:meta])* $response_field:ident: $response_type:ty),* $(,)?
+        }
+    ) => {
+        $crate::macros::command!(@struct $command_id, $(#[$event_meta])* $event {
+            $($(#[$event_field_meta])* $event_field: $event_type),*
+        });
+        $crate::macros::command!(@struct $command_id, $(#[$response_meta])* $response {
+            $($(#[$response_field_meta])* $response_field: $response_type),*
+        });
+        impl $crate::new_protocol::EventResponse for $event {
+            type Response = $response;
+            const EVENT_ID: u8 = $event_id;
+            $(const RESPONSE_ID: u8 = $response_id;)?
+            const EVENT_LEN: usize = $event::PAYLOAD_LEN;
+            const RESPONSE_LEN: Option<usize> = Some($response::PAYLOAD_LEN);
+
+            fn read_event_from<'a>(
+                packet: &'a $crate::new_protocol::Packet<'a>,
+            ) -> $crate::new_protocol::ProtocolResult<Self> {
+                Self::read_fields(packet)
+            }
+
+            fn read_response_from<'a>(
+                packet: &'a $crate::new_protocol::Packet<'a>,
+            ) -> $crate::new_protocol::ProtocolResult<Self::Response> {
+                $response::read_fields(packet)
+            }
+        }
+        #[cfg(test)]
+        paste::paste! {
+            #[test]
+            fn [<$event:snake _round_trips>]() {
+                $crate::macros::tests::check_round_trip::<$event>($command_id);
+            }
+        }
+    };
+    (
+        @struct $command_id:literal,
+        $(#[$meta:meta])* $name:ident {
+            $($(#[$field_meta:meta])* $field:ident: $type:ty),*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name {
+            $($(#[$field_meta])* pub $field: $type,)*
+        }
+        impl $name {
+            pub const COMMAND_ID: u8 = $command_id;
+            pub const PAYLOAD_LEN: usize =
+                0 $(+ <$type as $crate::new_protocol::PayloadField>::LEN)*;
+
+            fn read_fields(
+                packet: &$crate::new_protocol::Packet<'_>,
+            ) -> $crate::new_protocol::ProtocolResult<Self> {
+                let command_id = packet.get_command_id();
+                if command_id != Self::COMMAND_ID {
+                    return Err($crate::new_protocol::ProtocolError::UnrecognizedCommandId {
+                        prefix: packet.get_command_prefix(),
+                        command_id,
+                        packet: $crate::new_protocol::PacketBytes::new(packet.as_bytes()),
+                    });
+                }
+                #[allow(unused_mut, unused_variables)]
+                let mut data = packet.get_payload(Self::PAYLOAD_LEN)?;
+                Ok(Self {
+                    $($field: $crate::new_protocol::read_field(&mut data, packet)?,)*
+                })
+            }
+        }
+        impl $crate::new_protocol::CommandId for $name {
+            fn command_id(&self) -> u8 {
+                Self::COMMAND_ID
+            }
+        }
+        impl $crate::new_protocol::Writeable for $name {
+            fn write_to<W: $crate::new_protocol::Write>(
+                &self,
+                writer: &mut W,
+            ) -> Result<(), W::Error> {
+                writer.write_all(&[Self::COMMAND_ID])?;
+                $($crate::new_protocol::Writeable::write_to(&self.$field, writer)?;)*
+                Ok(())
+            }
+        }
+        impl core::fmt::Display for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                write!(f, "{}", stringify!($name))?;
+                $(write!(f, " {}={}", stringify!($field), self.$field)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use command;
+
+macro_rules! base_command {
+    (
+        $(#[$meta:meta])*
+        pub enum BaseCommand {
+            $($(#[$variant_meta:meta])* $variant:ident($command:ty),)*
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum BaseCommand {
+            $($(#[$variant_meta])* $variant($crate::new_protocol::Command<$command>),)*
+            /// Anything with a prefix that isn't modelled yet. Kept as is so it can be forwarded or
+            /// logged
+            Unknown {
+                prefix: u8,
+                command_id: u8,
+                payload: $crate::new_protocol::Payload,
+            },
+        }
+        impl BaseCommand {
+            /// Decodes a packet into the command matching its prefix after checking its checksum
+            pub fn decode<'a>(
+                packet: &'a $crate::new_protocol::Packet<'a>,
+            ) -> $crate::new_protocol::ProtocolResult<Self> {
+                packet.verify_checksum()?;
+                let prefix = packet.get_command_prefix();
+                $(
+                    if <$command as $crate::new_protocol::EventResponse>::payload_len(prefix)
+                        .is_some()
+                    {
+                        return Ok(BaseCommand::$variant($crate::new_protocol::Command::read_from(
+                            packet,
+                        )?));
+                    }
+                )*
+                Ok(BaseCommand::Unknown {
+                    prefix,
+                    command_id: packet.get_command_id(),
+                    payload: $crate::new_protocol::Payload::new(packet.get_data())?,
+                })
+            }
+
+            /// The number of payload bytes every packet with `prefix` has. None if the prefix
+            /// isn't known
+            pub fn expected_payload_len(prefix: u8) -> Option<usize> {
+                $(
+                    if let Some(len) =
+                        <$command as $crate::new_protocol::EventResponse>::payload_len(prefix)
+                    {
+                        return Some(len);
+                    }
+                )*
+                None
+            }
+        }
+        impl $crate::new_protocol::Writeable for BaseCommand {
+            fn write_to<W: $crate::new_protocol::Write>(
+                &self,
+                writer: &mut W,
+            ) -> Result<(), W::Error> {
+                match self {
+                    $(BaseCommand::$variant(command) => command.write_to(writer),)*
+                    BaseCommand::Unknown {
+                        prefix,
+                        command_id,
+                        payload,
+                    } => {
+                        writer.write_all(&[*prefix, *command_id])?;
+                        writer.write_all(payload.as_bytes())
+                    }
+                }
+            }
+        }
+        #[cfg(test)]
+        #[test]
+        fn registered_prefixes_are_unique() {
+            for prefix in 0..=u8::MAX {
+                let claimed = [$(
+                    <$command as $crate::new_protocol::EventResponse>::payload_len(prefix)
+                        .is_some(),
+                )*];
+                assert!(
+                    claimed.iter().filter(|claimed| **claimed).count() <= 1,
+                    "{prefix:#04x} is registered more than once"
+                );
+            }
+        }
+    };
+}
+pub(crate) use base_command;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::{
+        encoder::encode_packet,
+        height::Height,
+        new_protocol::{
+            tests::{packet, wire},
+            Command, CommandId, Connect, ConnectResponse, EventResponse, MoveFinished,
+            MoveFinishedAck, ProtocolError,
+        },
+    };
+    use core::fmt::Debug;
+    use std::vec::Vec;
+
+    /// The test [`command!`] generates. Reads the event and the response of `C` from packets
+    /// filled with 0x00 and then 0x01, and checks they encode back to the same bytes
+    pub(crate) fn check_round_trip<C>(command_id: u8)
+    where
+        C: EventResponse + Debug,
+        C::Response: Debug,
+    {
+        let response_len = C::RESPONSE_LEN.expect("declared commands have a response");
+        for fill in [0x00, 0x01] {
+            for (prefix, len) in [(C::EVENT_ID, C::EVENT_LEN), (C::RESPONSE_ID, response_len)] {
+                let mut body = Vec::from([prefix, command_id]);
+                body.resize(2 + len, fill);
+                let packet = packet(&body, 0x1234);
+                let command = Command::<C>::read_from(&packet.as_packet())
+                    .unwrap_or_else(|error| panic!("{body:02X?}: {error}"));
+                assert_eq!(
+                    encode_packet(&command, 0x1234).unwrap(),
+                    packet,
+                    "{command:?}"
+                );
+                assert_eq!(C::payload_len(prefix), Some(len));
+            }
+        }
+    }
+
+    command! {
+        prefix: 0x40,
+        response_prefix: 0x50,
+        command_id: 0x07,
+        /// Every kind of field and a response prefix that isn't the prefix plus one
+        event Probe {
+            level: u8,
+            height: Height,
+        }
+        response ProbeAck {
+            ok: bool,
+        }
+    }
+
+    /// Encodes `command`, then reads it back the way [`BaseCommand::decode`] does
+    ///
+    /// [`BaseCommand::decode`]: crate::new_protocol::BaseCommand::decode
+    fn round_trip<C: EventResponse>(command: &Command<C>) -> Command<C> {
+        let packet = encode_packet(command, 0x1234).unwrap();
+        Command::read_from(&packet.as_packet()).unwrap()
+    }
+
+    #[test]
+    fn generated_constants() {
+        assert_eq!(Probe::COMMAND_ID, 0x07);
+        assert_eq!(Probe::PAYLOAD_LEN, 3);
+        assert_eq!(ProbeAck::PAYLOAD_LEN, 1);
+        assert_eq!(<Probe as EventResponse>::EVENT_ID, 0x40);
+        assert_eq!(<Probe as EventResponse>::RESPONSE_ID, 0x50);
+        assert_eq!(<Connect as EventResponse>::RESPONSE_ID, 0x12);
+        assert_eq!(ConnectResponse { connected: true }.command_id(), 0x01);
+        assert_eq!(Connect::PAYLOAD_LEN, 0);
+    }
+
+    #[test]
+    fn matches_captures() {
+        let connect = Command::<Connect>::Command(Connect {});
+        assert_eq!(
+            wire(&connect, 0x013B),
+            [0xFA, 0x11, 0x01, 0x01, 0x3B, 0x2A, 0xFD]
+        );
+        let connected = Command::<Connect>::Reponse(ConnectResponse { connected: true });
+        assert_eq!(
+            wire(&connected, 0x1793),
+            [0xFA, 0x12, 0x01, 0x01, 0x17, 0x93, 0x96, 0xFD]
+        );
+        let finished = Command::<MoveFinished>::Command(MoveFinished {});
+        assert_eq!(
+            wire(&finished, 0x02AA),
+            [0xFA, 0xA0, 0x00, 0x02, 0xAA, 0x08, 0xFD]
+        );
+        let ack = Command::<MoveFinished>::Reponse(MoveFinishedAck {});
+        assert_eq!(
+            wire(&ack, 0x06DF),
+            [0xFA, 0xA1, 0x00, 0x06, 0xDF, 0x78, 0xFD]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        assert!(matches!(
+            round_trip(&Command::<Connect>::Command(Connect {})),
+            Command::Command(Connect {})
+        ));
+        for connected in [true, false] {
+            assert!(matches!(
+                round_trip(&Command::<Connect>::Reponse(ConnectResponse { connected })),
+                Command::Reponse(response) if response.connected == connected
+            ));
+        }
+
+        let probe = Probe {
+            level: 9,
+            height: Height::from_mm(0x02FE),
+        };
+        assert!(matches!(
+            round_trip(&Command::<Probe>::Command(probe)),
+            Command::Command(read) if read == probe
+        ));
+        assert!(matches!(
+            round_trip(&Command::<Probe>::Reponse(ProbeAck { ok: true })),
+            Command::Reponse(ProbeAck { ok: true })
+        ));
+    }
+
+    #[test]
+    fn wrong_command_id() {
+        let body = [0x11, 0x02];
+        let wrong = packet(&body, 1);
+        let error = Command::<Connect>::read_from(&wrong.as_packet()).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedCommandId { prefix: 0x11, command_id: 0x02, packet }
+                if packet.as_bytes() == wrong.as_bytes()
+        ));
+        assert_eq!(
+            std::format!("{error}"),
+            "unrecognized command id 0x02 for command 0x11: FA 11 02 00 01 12 FD"
+        );
+    }
+
+    #[test]
+    fn wrong_payload_length() {
+        let short = packet(&[0x12, 0x01], 1);
+        assert!(matches!(
+            Command::<Connect>::read_from(&short.as_packet()),
+            Err(ProtocolError::UnexpectedPayloadLength {
+                expected: 1,
+                actual: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn display() {
+        let probe = Probe {
+            level: 9,
+            height: Height::from_mm(724),
+        };
+        assert_eq!(
+            std::format!("{probe}"),
+            std::format!("Probe level=9 height={}", Height::from_mm(724))
+        );
+        assert_eq!(std::format!("{}", Connect {}), "Connect");
+    }
+}
@@ -0,0 +1,409 @@
+//! Moving the desk to an absolute height
+//!
+//! The desk only knows Up and Down, so [`MoveTo`] holds the right key down, watches the
+//! [`ReportHeight`] packets the desk sends about every 100ms and lets go just before the target.
+//! The desk keeps moving for a moment after the key is released (about 1cm in the captures), so
+//! the key is released early by the overshoot, and the overshoot is re-measured after every stop.
+//! If the desk settles outside of the tolerance the move is corrected with another press.
+//!
+//! Nothing is sent directly. Feed every packet from the desk to [`MoveTo::observe`] and call
+//! [`MoveTo::poll`] regularly, sending any key event it returns. Times are milliseconds from any
+//! monotonic clock.
+//!
+//! [`ReportHeight`]: crate::new_protocol::ReportHeight
+
+use crate::{
+    height::{Height, HeightLimits},
+    new_protocol::{BaseCommand, ChangeHeight, ChangeHeightState, Command, ProtocolResult},
+    preset::KEY_TAP_MS,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveConfig {
+    /// How close to the target counts as there
+    pub tolerance: Height,
+    /// How far the desk keeps moving after the key is released. Only the starting guess, see
+    /// [`MoveTo::overshoot`]
+    pub overshoot: Height,
+    /// Gives up on the whole move after this long, including any corrections
+    pub timeout_ms: u64,
+    /// After releasing the key the desk is treated as stopped once it says it has finished
+    /// moving or the height hasn't changed for this long
+    pub settle_ms: u64,
+    /// How many extra presses to make when the desk stops outside of the tolerance
+    pub max_corrections: u8,
+}
+impl Default for MoveConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+impl MoveConfig {
+    pub const DEFAULT: Self = Self {
+        tolerance: Height::from_mm(3),
+        // The captured desk moved from 76.6cm to 77.7cm after Up was released
+        overshoot: Height::from_mm(10),
+        // The captured desk moves about 2.5cm/s so the full 60cm range takes about 24s
+        timeout_ms: 30_000,
+        settle_ms: 500,
+        max_corrections: 2,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveOutcome {
+    /// Stopped within the tolerance of the target
+    Reached(Height),
+    /// Stopped outside of the tolerance and out of corrections
+    Missed(Height),
+    /// The last height the desk reported, if any
+    TimedOut(Option<Height>),
+    /// The last height the desk reported, if any
+    Cancelled(Option<Height>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// No key is held
+    Idle,
+    Moving {
+        key: ChangeHeight<()>,
+        pressed_ms: u64,
+    },
+    /// The key has been released and the desk is coasting to a stop
+    Settling {
+        released_at: Option<Height>,
+        since_ms: u64,
+    },
+    Done(MoveOutcome),
+}
+
+/// A single move to a target height
+#[derive(Debug, Clone)]
+pub struct MoveTo {
+    target: Height,
+    config: MoveConfig,
+    phase: Phase,
+    started_ms: Option<u64>,
+    height: Option<Height>,
+    height_changed_ms: u64,
+    /// The desk has said it finished moving since the key was released
+    finished: bool,
+    presses: u8,
+    cancelled: bool,
+}
+impl MoveTo {
+    /// Fails if `target` is outside of `limits` since the desk would stop short of it
+    pub fn new(target: Height, limits: &HeightLimits, config: MoveConfig) -> ProtocolResult<Self> {
+        Ok(Self {
+            target: limits.check(target)?,
+            config,
+            phase: Phase::Idle,
+            started_ms: None,
+            height: None,
+            height_changed_ms: 0,
+            finished: false,
+            presses: 0,
+            cancelled: false,
+        })
+    }
+
+    pub fn target(&self) -> Height {
+        self.target
+    }
+
+    /// The overshoot measured so far. Pass it on in the [`MoveConfig`] of the next move
+    pub fn overshoot(&self) -> Height {
+        self.config.overshoot
+    }
+
+    /// The last height the desk reported
+    pub fn height(&self) -> Option<Height> {
+        self.height
+    }
+
+    /// How the move ended, once it has
+    pub fn outcome(&self) -> Option<MoveOutcome> {
+        match self.phase {
+            Phase::Done(outcome) => Some(outcome),
+            _ => None,
+        }
+    }
+
+    /// Releases the key on the next poll and ends with [`MoveOutcome::Cancelled`] once the desk
+    /// has stopped
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+    }
+
+    /// Updates the move from a packet the desk sent. Everything else is ignored
+    pub fn observe(&mut self, command: &BaseCommand, now_ms: u64) {
+        match command {
+            BaseCommand::ReportHeight(Command::Command(report)) => {
+                if self.height != Some(report.height) {
+                    self.height_changed_ms = now_ms;
+                }
+                self.height = Some(report.height);
+            }
+            BaseCommand::MoveFinished(Command::Command(_)) => self.finished = true,
+            _ => {}
+        }
+    }
+
+    /// The key event to send now, if any
+    pub fn poll(&mut self, now_ms: u64) -> Option<ChangeHeight> {
+        let started_ms = *self.started_ms.get_or_insert(now_ms);
+        let timed_out = now_ms.saturating_sub(started_ms) >= self.config.timeout_ms;
+        match self.phase {
+            Phase::Idle => self.start(timed_out, now_ms),
+            Phase::Moving { key, pressed_ms } => {
+                // Hold the key for at least a tap so the desk sees it
+                let held = now_ms.saturating_sub(pressed_ms) >= KEY_TAP_MS;
+                if self.cancelled || timed_out || (held && self.should_release(key)) {
+                    self.phase = Phase::Settling {
+                        released_at: self.height,
+                        since_ms: now_ms,
+                    };
+                    self.finished = false;
+                    Some(key.with_state(ChangeHeightState::Stop))
+                } else {
+                    None
+                }
+            }
+            Phase::Settling {
+                released_at,
+                since_ms,
+            } => {
+                let still_ms = now_ms.saturating_sub(since_ms.max(self.height_changed_ms));
+                if !self.finished && still_ms < self.config.settle_ms {
+                    return None;
+                }
+                if let (Some(released_at), Some(height)) = (released_at, self.height) {
+                    self.learn_overshoot(released_at.abs_diff(height));
+                }
+                self.phase = Phase::Idle;
+                self.start(timed_out, now_ms)
+            }
+            Phase::Done(_) => None,
+        }
+    }
+
+    /// Presses the key towards the target, or finishes if there is nothing left to do
+    fn start(&mut self, timed_out: bool, now_ms: u64) -> Option<ChangeHeight> {
+        let outcome = if self.cancelled {
+            MoveOutcome::Cancelled(self.height)
+        } else if timed_out {
+            MoveOutcome::TimedOut(self.height)
+        } else {
+            // Wait for the desk to say where it is
+            let height = self.height?;
+            if height.abs_diff(self.target) <= self.config.tolerance {
+                MoveOutcome::Reached(height)
+            } else if self.presses > self.config.max_corrections {
+                MoveOutcome::Missed(height)
+            } else {
+                let key = if height < self.target {
+                    ChangeHeight::Up(())
+                } else {
+                    ChangeHeight::Down(())
+                };
+                self.presses += 1;
+                self.phase = Phase::Moving {
+                    key,
+                    pressed_ms: now_ms,
+                };
+                return Some(key.with_state(ChangeHeightState::Start));
+            }
+        };
+        self.phase = Phase::Done(outcome);
+        None
+    }
+
+    /// Whether the desk is close enough to the target to coast the rest of the way
+    fn should_release(&self, key: ChangeHeight<()>) -> bool {
+        let Some(height) = self.height else {
+            return false;
+        };
+        let overshoot = self.config.overshoot.to_mm();
+        match key {
+            ChangeHeight::Up(()) => height.to_mm().saturating_add(overshoot) >= self.target.to_mm(),
+            ChangeHeight::Down(()) => {
+                height.to_mm() <= self.target.to_mm().saturating_add(overshoot)
+            }
+            // Only Up and Down are ever pressed
+            _ => true,
+        }
+    }
+
+    /// Averages the measured coast into the overshoot so one odd stop doesn't throw it off
+    fn learn_overshoot(&mut self, coasted: Height) {
+        let average = (self.config.overshoot.to_mm() as u32 + coasted.to_mm() as u32).div_ceil(2);
+        self.config.overshoot = Height::from_mm(average as u16);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_protocol::{MoveFinished, ProtocolError, ReportHeight};
+
+    const UP: ChangeHeight<()> = ChangeHeight::Up(());
+    const DOWN: ChangeHeight<()> = ChangeHeight::Down(());
+
+    fn report(mm: u16) -> BaseCommand {
+        BaseCommand::ReportHeight(Command::Command(ReportHeight::new(Height::from_mm(mm))))
+    }
+
+    fn finished() -> BaseCommand {
+        BaseCommand::MoveFinished(Command::Command(MoveFinished {}))
+    }
+
+    fn move_to(mm: u16, config: MoveConfig) -> MoveTo {
+        MoveTo::new(Height::from_mm(mm), &HeightLimits::DEFAULT, config).unwrap()
+    }
+
+    /// Reports `heights` 100ms apart starting at `from_ms`, polling after each. Gives the first
+    /// key event and when it was sent
+    fn drive(mover: &mut MoveTo, from_ms: u64, heights: &[u16]) -> Option<(u64, ChangeHeight)> {
+        for (index, &mm) in heights.iter().enumerate() {
+            let now_ms = from_ms + index as u64 * 100;
+            mover.observe(&report(mm), now_ms);
+            if let Some(key) = mover.poll(now_ms) {
+                return Some((now_ms, key));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn outside_of_limits() {
+        let error = MoveTo::new(
+            Height::from_mm(1300),
+            &HeightLimits::DEFAULT,
+            MoveConfig::DEFAULT,
+        )
+        .unwrap_err();
+        assert!(matches!(error, ProtocolError::HeightOutOfRange { .. }));
+    }
+
+    #[test]
+    fn waits_for_a_height() {
+        let mut mover = move_to(800, MoveConfig::DEFAULT);
+        assert_eq!(mover.poll(0), None);
+        assert_eq!(mover.outcome(), None);
+    }
+
+    #[test]
+    fn reached() {
+        let mut mover = move_to(800, MoveConfig::DEFAULT);
+        assert_eq!(
+            drive(&mut mover, 0, &[724]),
+            Some((0, UP.with_state(ChangeHeightState::Start)))
+        );
+        // Released once the overshoot would carry it the rest of the way
+        assert_eq!(
+            drive(&mut mover, 100, &[750, 770, 780, 790]),
+            Some((400, UP.with_state(ChangeHeightState::Stop)))
+        );
+        mover.observe(&report(798), 500);
+        assert_eq!(mover.poll(500), None);
+        mover.observe(&finished(), 550);
+        assert_eq!(mover.poll(550), None);
+        assert_eq!(
+            mover.outcome(),
+            Some(MoveOutcome::Reached(Height::from_mm(798)))
+        );
+        // Coasted 8mm, averaged with the 10mm guess
+        assert_eq!(mover.overshoot(), Height::from_mm(9));
+    }
+
+    #[test]
+    fn holds_for_a_tap() {
+        // Already within the overshoot, but the desk wouldn't notice a shorter press
+        let mut mover = move_to(730, MoveConfig::DEFAULT);
+        assert_eq!(
+            drive(&mut mover, 0, &[724]),
+            Some((0, UP.with_state(ChangeHeightState::Start)))
+        );
+        mover.observe(&report(724), 50);
+        assert_eq!(mover.poll(50), None);
+        assert_eq!(
+            mover.poll(KEY_TAP_MS),
+            Some(UP.with_state(ChangeHeightState::Stop))
+        );
+    }
+
+    #[test]
+    fn corrects_then_misses() {
+        let config = MoveConfig {
+            max_corrections: 1,
+            ..MoveConfig::DEFAULT
+        };
+        let mut mover = move_to(800, config);
+        drive(&mut mover, 0, &[724]);
+        assert_eq!(
+            drive(&mut mover, 100, &[795]),
+            Some((100, UP.with_state(ChangeHeightState::Stop)))
+        );
+        // Coasts well past and settles without saying it has finished
+        mover.observe(&report(830), 200);
+        assert_eq!(mover.poll(200), None);
+        assert_eq!(
+            mover.poll(700),
+            Some(DOWN.with_state(ChangeHeightState::Start))
+        );
+        assert_eq!(mover.overshoot(), Height::from_mm(23));
+        assert_eq!(
+            drive(&mut mover, 800, &[820]),
+            Some((800, DOWN.with_state(ChangeHeightState::Stop)))
+        );
+        mover.observe(&report(780), 900);
+        mover.observe(&finished(), 900);
+        assert_eq!(mover.poll(900), None);
+        assert_eq!(
+            mover.outcome(),
+            Some(MoveOutcome::Missed(Height::from_mm(780)))
+        );
+    }
+
+    #[test]
+    fn timed_out() {
+        let config = MoveConfig {
+            timeout_ms: 1000,
+            ..MoveConfig::DEFAULT
+        };
+        let mut mover = move_to(800, config);
+        drive(&mut mover, 0, &[724]);
+        // The desk never moves
+        assert_eq!(
+            drive(&mut mover, 100, &[724; 10]),
+            Some((1000, UP.with_state(ChangeHeightState::Stop)))
+        );
+        assert_eq!(mover.poll(1100), None);
+        assert_eq!(mover.poll(1500), None);
+        assert_eq!(
+            mover.outcome(),
+            Some(MoveOutcome::TimedOut(Some(Height::from_mm(724))))
+        );
+    }
+
+    #[test]
+    fn cancelled() {
+        let mut mover = move_to(800, MoveConfig::DEFAULT);
+        drive(&mut mover, 0, &[724]);
+        mover.observe(&report(740), 100);
+        mover.cancel();
+        assert_eq!(
+            mover.poll(100),
+            Some(UP.with_state(ChangeHeightState::Stop))
+        );
+        mover.observe(&report(750), 200);
+        mover.observe(&finished(), 200);
+        assert_eq!(mover.poll(200), None);
+        assert_eq!(
+            mover.outcome(),
+            Some(MoveOutcome::Cancelled(Some(Height::from_mm(750))))
+        );
+        assert_eq!(mover.poll(300), None);
+    }
+}
@@ -1,91 +1,566 @@
 //! this is some module stuff
 
+use crate::{
+    height::Height,
+    macros::{base_command, command},
+};
+
+/// Somewhere packets can be written, such as a uart
+///
+/// With the `embedded-io` feature any `embedded_io::Write` can be used through `io::IoWriter`.
 pub trait Write {
-    fn write_all(&self, buf: &[u8]) -> Result<(), ProtocolError>;
+    type Error;
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
 }
 
 pub trait Writeable {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()>;
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error>;
 }
 impl Writeable for () {
-    fn write_to<W: Write>(&self, _writer: &mut W) -> ProtocolResult<()> {
+    fn write_to<W: Write>(&self, _writer: &mut W) -> Result<(), W::Error> {
         Ok(())
     }
 }
 impl Writeable for u32 {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
         let bytes = self.to_be_bytes();
         writer.write_all(&[bytes[0], bytes[1], bytes[2], bytes[3]])
     }
 }
 impl Writeable for u16 {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
         let bytes = self.to_be_bytes();
         writer.write_all(&[bytes[0], bytes[1]])
     }
 }
+impl Writeable for u8 {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer.write_all(&[*self])
+    }
+}
 impl Writeable for bool {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
         writer.write_all(&[*self as u8])
     }
 }
 
+/// A fixed size value in the payload of a command declared with `command!`
+pub trait PayloadField: Writeable + Copy {
+    const LEN: usize;
+
+    /// Fails with [`ProtocolError::UnexpectedPayloadLength`] unless `bytes` is
+    /// [`PayloadField::LEN`] long
+    fn read(bytes: &[u8]) -> ProtocolResult<Self>;
+}
+/// The bytes of a field, checked to be the right length
+fn field_bytes<const N: usize>(bytes: &[u8]) -> ProtocolResult<[u8; N]> {
+    bytes
+        .try_into()
+        .map_err(|_| ProtocolError::UnexpectedPayloadLength {
+            expected: N,
+            actual: bytes.len(),
+            packet: PacketBytes::new(bytes),
+        })
+}
+impl PayloadField for u8 {
+    const LEN: usize = 1;
+
+    fn read(bytes: &[u8]) -> ProtocolResult<Self> {
+        let [byte] = field_bytes(bytes)?;
+        Ok(byte)
+    }
+}
+impl PayloadField for u16 {
+    const LEN: usize = 2;
+
+    fn read(bytes: &[u8]) -> ProtocolResult<Self> {
+        field_bytes(bytes).map(u16::from_be_bytes)
+    }
+}
+impl PayloadField for bool {
+    const LEN: usize = 1;
+
+    fn read(bytes: &[u8]) -> ProtocolResult<Self> {
+        match field_bytes(bytes)? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            [flag] => Err(ProtocolError::InvalidFlag {
+                flag,
+                packet: PacketBytes::new(bytes),
+            }),
+        }
+    }
+}
+/// Reads the next field of `packet` off of the front of `data`
+pub(crate) fn read_field<T: PayloadField>(
+    data: &mut &[u8],
+    packet: &Packet<'_>,
+) -> ProtocolResult<T> {
+    // The payload length is checked against the sum of the fields before any are read
+    let (bytes, rest) = data.split_at(T::LEN);
+    *data = rest;
+    T::read(bytes).map_err(|error| error.in_packet(packet))
+}
+
+/// Everything that can go wrong reading or building a packet
+///
+/// Errors about a value read from a packet keep a copy of the packet. Where the value didn't come
+/// from a packet, such as `Register::try_from(0x30)`, the copy is just the value.
 #[derive(Clone, Debug)]
 pub enum ProtocolError {
-    UnrecognizedCommand(u8),
-    UnrecognizedChangeHeightCommand(u8),
-    UnrecognizedReportHeightCommand(u8),
-    UnrecognizedMoveState(u8),
-    // BadCheckSum(Command),
-    UnrecognizedResponseState(u8),
+    /// A prefix that isn't the event or response being read
+    UnrecognizedCommand {
+        prefix: u8,
+        packet: PacketBytes,
+    },
+    /// A command id that doesn't belong to the command with this prefix
+    UnrecognizedCommandId {
+        prefix: u8,
+        command_id: u8,
+        packet: PacketBytes,
+    },
+    UnrecognizedChangeHeightCommand {
+        command_id: u8,
+        packet: PacketBytes,
+    },
+    UnrecognizedReportHeightCommand {
+        command_id: u8,
+        packet: PacketBytes,
+    },
+    UnrecognizedMoveState {
+        state: u8,
+        packet: PacketBytes,
+    },
+    UnrecognizedResponseState {
+        state: u8,
+        packet: PacketBytes,
+    },
+    UnrecognizedRegister {
+        register: u8,
+        packet: PacketBytes,
+    },
+    /// A boolean field that wasn't 0 or 1
+    InvalidFlag {
+        flag: u8,
+        packet: PacketBytes,
+    },
+    BadCheckSum {
+        expected: u8,
+        actual: u8,
+        packet: PacketBytes,
+    },
+    PacketTooShort(PacketBytes),
+    /// Only the first [`MAX_PACKET_LEN`] bytes of the packet are kept
+    PacketTooLong(PacketBytes),
+    /// More than [`MAX_PAYLOAD_LEN`] bytes for a [`Payload`]. Only the first [`MAX_PACKET_LEN`]
+    /// bytes of the payload are kept
+    PayloadTooLong {
+        len: usize,
+        payload: PacketBytes,
+    },
+    MissingStartTag(PacketBytes),
+    MissingEndTag(PacketBytes),
+    UnexpectedPayloadLength {
+        expected: usize,
+        actual: usize,
+        packet: PacketBytes,
+    },
+    /// Negative, not a number or too big to send
+    InvalidHeight(f32),
+    HeightOutOfRange {
+        height: Height,
+        min: Height,
+        max: Height,
+    },
+    /// The desk hasn't answered for this register yet
+    MissingRegister(Register),
 }
 impl core::fmt::Display for ProtocolError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.write_str("Protocol Error")
+        match self {
+            ProtocolError::UnrecognizedCommand { prefix, packet } => {
+                write!(f, "unrecognized command {prefix:#04x}: {packet}")
+            }
+            ProtocolError::UnrecognizedCommandId {
+                prefix,
+                command_id,
+                packet,
+            } => write!(
+                f,
+                "unrecognized command id {command_id:#04x} for command {prefix:#04x}: {packet}"
+            ),
+            ProtocolError::UnrecognizedChangeHeightCommand { command_id, packet } => write!(
+                f,
+                "unrecognized change height command id {command_id:#04x}: {packet}"
+            ),
+            ProtocolError::UnrecognizedReportHeightCommand { command_id, packet } => write!(
+                f,
+                "unrecognized report height command id {command_id:#04x}: {packet}"
+            ),
+            ProtocolError::UnrecognizedMoveState { state, packet } => write!(
+                f,
+                "unrecognized key state {state:#04x}, expected 0x00 or 0x01: {packet}"
+            ),
+            ProtocolError::UnrecognizedResponseState { state, packet } => {
+                write!(f, "unrecognized response state {state:#04x}: {packet}")
+            }
+            ProtocolError::UnrecognizedRegister { register, packet } => {
+                write!(
+                    f,
+                    "unknown configuration register {register:#04x}: {packet}"
+                )
+            }
+            ProtocolError::InvalidFlag { flag, packet } => {
+                write!(f, "{flag:#04x} is not a valid flag: {packet}")
+            }
+            ProtocolError::BadCheckSum {
+                expected,
+                actual,
+                packet,
+            } => write!(
+                f,
+                "bad checksum, computed {expected:#04x} but the packet has {actual:#04x}: {packet}"
+            ),
+            ProtocolError::PacketTooShort(packet) => write!(
+                f,
+                "packet is {} bytes, shorter than the minimum of {MIN_PACKET_LEN}: {packet}",
+                packet.as_bytes().len()
+            ),
+            ProtocolError::PacketTooLong(packet) => {
+                write!(
+                    f,
+                    "packet is longer than {MAX_PACKET_LEN} bytes: {packet}.."
+                )
+            }
+            ProtocolError::PayloadTooLong { len, payload } => {
+                write!(
+                    f,
+                    "payload is {len} bytes, longer than the maximum of {MAX_PAYLOAD_LEN}: {payload}"
+                )?;
+                if *len > payload.as_bytes().len() {
+                    f.write_str("..")?;
+                }
+                Ok(())
+            }
+            ProtocolError::MissingStartTag(packet) => {
+                write!(f, "packet does not start with {START_TAG:#04x}: {packet}")
+            }
+            ProtocolError::MissingEndTag(packet) => write!(
+                f,
+                "packet was cut off before the {END_TAG:#04x} end tag: {packet}"
+            ),
+            ProtocolError::UnexpectedPayloadLength {
+                expected,
+                actual,
+                packet,
+            } => write!(
+                f,
+                "expected a {expected} byte payload but got {actual} bytes: {packet}"
+            ),
+            ProtocolError::InvalidHeight(height) => write!(f, "{height} is not a valid height"),
+            ProtocolError::HeightOutOfRange { height, min, max } => {
+                write!(
+                    f,
+                    "{height} is outside of the desk's limits of {min} to {max}"
+                )
+            }
+            ProtocolError::MissingRegister(register) => {
+                write!(f, "no value for register {:#04x}", *register as u8)
+            }
+        }
+    }
+}
+impl core::error::Error for ProtocolError {}
+impl ProtocolError {
+    /// Replaces the copy kept by an error about a single value with the whole packet it came from
+    pub(crate) fn in_packet(mut self, packet: &Packet<'_>) -> Self {
+        match &mut self {
+            ProtocolError::UnrecognizedCommand { packet: bytes, .. }
+            | ProtocolError::UnrecognizedCommandId { packet: bytes, .. }
+            | ProtocolError::UnrecognizedChangeHeightCommand { packet: bytes, .. }
+            | ProtocolError::UnrecognizedReportHeightCommand { packet: bytes, .. }
+            | ProtocolError::UnrecognizedMoveState { packet: bytes, .. }
+            | ProtocolError::UnrecognizedResponseState { packet: bytes, .. }
+            | ProtocolError::UnrecognizedRegister { packet: bytes, .. }
+            | ProtocolError::InvalidFlag { packet: bytes, .. } => {
+                *bytes = PacketBytes::new(packet.as_bytes())
+            }
+            _ => {}
+        }
+        self
     }
 }
 pub type ProtocolResult<T> = Result<T, ProtocolError>;
 
-// Will be valic with first byte being 0xFA and last being 0xFD
+/// An error from the protocol or from the reader or writer underneath it
+#[derive(Debug, Clone)]
+pub enum IoError<E> {
+    Protocol(ProtocolError),
+    Io(E),
+    /// The reader has no more bytes
+    Eof,
+}
+impl<E> From<ProtocolError> for IoError<E> {
+    fn from(error: ProtocolError) -> Self {
+        IoError::Protocol(error)
+    }
+}
+impl<E: core::fmt::Display> core::fmt::Display for IoError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            IoError::Protocol(error) => error.fmt(f),
+            IoError::Io(error) => write!(f, "io error: {error}"),
+            IoError::Eof => write!(f, "unexpected end of input"),
+        }
+    }
+}
+impl<E: core::fmt::Debug + core::fmt::Display> core::error::Error for IoError<E> {}
+
+/// Which side of the link sent a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Desk,
+    /// The keypad, or anything else standing in for it
+    Controller,
+}
+
+pub const START_TAG: u8 = 0xFA;
+pub const END_TAG: u8 = 0xFD;
+/// Escapes a start tag, end tag or escape byte that appears inside of a packet
+pub const ESCAPE: u8 = 0xFE;
+/// Start tag, prefix, command id, two byte packet number, checksum and end tag
+pub const MIN_PACKET_LEN: usize = 7;
+/// Longest packet seen in the captures is 10 bytes, this leaves some room for unknown commands
+pub const MAX_PACKET_LEN: usize = 16;
+pub const MAX_PAYLOAD_LEN: usize = MAX_PACKET_LEN - MIN_PACKET_LEN;
+/// The longest a packet can be on the wire, if every byte between the tags is escaped
+pub const MAX_ESCAPED_LEN: usize = 2 * MAX_PACKET_LEN;
+
+/// A copy of the bytes of a packet that could not be read. Cut off after [`MAX_PACKET_LEN`] bytes
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PacketBytes {
+    bytes: [u8; MAX_PACKET_LEN],
+    len: u8,
+}
+impl PacketBytes {
+    pub fn new(bytes: &[u8]) -> Self {
+        let len = bytes.len().min(MAX_PACKET_LEN);
+        let mut copy = [0; MAX_PACKET_LEN];
+        copy[..len].copy_from_slice(&bytes[..len]);
+        Self {
+            bytes: copy,
+            len: len as u8,
+        }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+impl core::fmt::Debug for PacketBytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:02X?}", self.as_bytes())
+    }
+}
+impl core::fmt::Display for PacketBytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (index, byte) in self.as_bytes().iter().enumerate() {
+            if index != 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The payload of a command that isn't modelled yet
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Payload {
+    bytes: [u8; MAX_PAYLOAD_LEN],
+    len: u8,
+}
+impl Payload {
+    pub const EMPTY: Self = Self {
+        bytes: [0; MAX_PAYLOAD_LEN],
+        len: 0,
+    };
+
+    pub fn new(bytes: &[u8]) -> ProtocolResult<Self> {
+        if bytes.len() > MAX_PAYLOAD_LEN {
+            return Err(ProtocolError::PayloadTooLong {
+                len: bytes.len(),
+                payload: PacketBytes::new(bytes),
+            });
+        }
+        let mut copy = [0; MAX_PAYLOAD_LEN];
+        copy[..bytes.len()].copy_from_slice(bytes);
+        Ok(Self {
+            bytes: copy,
+            len: bytes.len() as u8,
+        })
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+impl core::fmt::Debug for Payload {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:02X?}", self.as_bytes())
+    }
+}
+
+/// A read only view of a packet that has been unescaped but still has its start and end tags
+///
+/// Construction checks the tags and the length so none of the accessors can panic.
+#[derive(Debug, Clone, Copy)]
 pub struct Packet<'a> {
-    raw_data: &'a mut [u8],
+    raw_data: &'a [u8],
 }
 impl<'a> Packet<'a> {
-    fn get_command_prefix(&self) -> u8 {
+    /// Checks that `raw_data` has both tags and is long enough to hold every field
+    pub fn new(raw_data: &'a [u8]) -> ProtocolResult<Self> {
+        match raw_data {
+            [START_TAG, .., END_TAG] if raw_data.len() >= MIN_PACKET_LEN => Ok(Self { raw_data }),
+            [START_TAG, .., END_TAG] | [START_TAG] => {
+                Err(ProtocolError::PacketTooShort(PacketBytes::new(raw_data)))
+            }
+            [START_TAG, ..] => Err(ProtocolError::MissingEndTag(PacketBytes::new(raw_data))),
+            _ => Err(ProtocolError::MissingStartTag(PacketBytes::new(raw_data))),
+        }
+    }
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.raw_data
+    }
+    pub fn get_command_prefix(&self) -> u8 {
         self.raw_data[1]
     }
-    fn get_command_id(&self) -> u8 {
+    pub fn get_command_id(&self) -> u8 {
         self.raw_data[2]
     }
-    fn get_checksum(&self) -> u8 {
+    pub fn get_checksum(&self) -> u8 {
         self.raw_data[self.raw_data.len() - 2]
     }
-    fn get_packet_num(&self) -> u16 {
+    pub fn get_packet_num(&self) -> u16 {
         let len = self.raw_data.len();
-        let slice = &self.raw_data[len - 4..=len - 3];
-        u16::from_be_bytes([slice[0], slice[1]])
+        u16::from_be_bytes([self.raw_data[len - 4], self.raw_data[len - 3]])
     }
-    fn get_data(&self) -> &[u8] {
+    /// Everything between the command id and the packet number
+    pub fn get_data(&self) -> &'a [u8] {
         let len = self.raw_data.len();
         &self.raw_data[3..len - 4]
     }
-    fn validate_checksum(&self) -> ValidChecksum {
-        let len = self.raw_data.len();
-        // -2 to exclude the end tag and the chesksum itself
-        let computed_checksum = self.raw_data[1..len - 2].iter().fold(0, |acc, &b| acc ^ b);
-        if computed_checksum == self.get_checksum() {
+    /// The payload, checked to be `len` bytes long
+    pub(crate) fn get_payload(&self, len: usize) -> ProtocolResult<&[u8]> {
+        let data = self.get_data();
+        if data.len() != len {
+            return Err(ProtocolError::UnexpectedPayloadLength {
+                expected: len,
+                actual: data.len(),
+                packet: PacketBytes::new(self.raw_data),
+            });
+        }
+        Ok(data)
+    }
+    fn get_data_array<const N: usize>(&self) -> ProtocolResult<[u8; N]> {
+        let data = self.get_data();
+        data.try_into()
+            .map_err(|_| ProtocolError::UnexpectedPayloadLength {
+                expected: N,
+                actual: data.len(),
+                packet: PacketBytes::new(self.raw_data),
+            })
+    }
+    fn compute_checksum(&self) -> u8 {
+        compute_checksum(self.raw_data)
+    }
+    pub fn validate_checksum(&self) -> ValidChecksum {
+        if self.compute_checksum() == self.get_checksum() {
             ValidChecksum::Valid
         } else {
             ValidChecksum::Invalid
         }
     }
-    fn insert_checksum(&mut self) {
-        let len = self.raw_data.len();
-        let computed_checksum = self.raw_data[1..len - 2].iter().fold(0, |acc, &b| acc ^ b);
-        self.raw_data[len - 2] = computed_checksum;
+    pub fn verify_checksum(&self) -> ProtocolResult<()> {
+        match self.validate_checksum() {
+            ValidChecksum::Valid => Ok(()),
+            ValidChecksum::Invalid => Err(ProtocolError::BadCheckSum {
+                expected: self.compute_checksum(),
+                actual: self.get_checksum(),
+                packet: PacketBytes::new(self.raw_data),
+            }),
+        }
+    }
+}
+
+fn compute_checksum(raw_data: &[u8]) -> u8 {
+    let len = raw_data.len();
+    // -2 to exclude the end tag and the chesksum itself
+    raw_data[1..len - 2].iter().fold(0, |acc, &b| acc ^ b)
+}
+
+/// An owned packet that holds up to `N` bytes, for storing packets in queues
+///
+/// Like [`Packet`] it has been unescaped and still has its start and end tags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketBuf<const N: usize = MAX_PACKET_LEN> {
+    buf: [u8; N],
+    len: usize,
+}
+impl<const N: usize> PacketBuf<N> {
+    /// Copies `raw_data` after doing the same checks as [`Packet::new`]
+    pub fn new(raw_data: &[u8]) -> ProtocolResult<Self> {
+        Packet::new(raw_data)?;
+        if raw_data.len() > N {
+            return Err(ProtocolError::PacketTooLong(PacketBytes::new(raw_data)));
+        }
+        let mut buf = [0; N];
+        buf[..raw_data.len()].copy_from_slice(raw_data);
+        Ok(Self {
+            buf,
+            len: raw_data.len(),
+        })
+    }
+    pub fn as_packet(&self) -> Packet<'_> {
+        Packet {
+            raw_data: self.as_bytes(),
+        }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+    pub fn get_command_prefix(&self) -> u8 {
+        self.as_packet().get_command_prefix()
+    }
+    pub fn get_command_id(&self) -> u8 {
+        self.as_packet().get_command_id()
+    }
+    pub fn get_checksum(&self) -> u8 {
+        self.as_packet().get_checksum()
+    }
+    pub fn get_packet_num(&self) -> u16 {
+        self.as_packet().get_packet_num()
+    }
+    pub fn get_data(&self) -> &[u8] {
+        self.as_packet().get_data()
+    }
+    pub(crate) fn insert_checksum(&mut self) {
+        self.buf[self.len - 2] = compute_checksum(self.as_bytes());
+    }
+    /// The tags must be left alone
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
     }
 }
+impl<'a, const N: usize> TryFrom<Packet<'a>> for PacketBuf<N> {
+    type Error = ProtocolError;
 
+    fn try_from(packet: Packet<'a>) -> ProtocolResult<Self> {
+        Self::new(packet.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValidChecksum {
     Valid,
     Invalid,
@@ -104,6 +579,22 @@ pub trait EventResponse: CommandId + Writeable {
     type Response: CommandId + Writeable;
     const EVENT_ID: u8;
     const RESPONSE_ID: u8 = Self::EVENT_ID + 1;
+    /// The number of payload bytes after the command id of the event
+    const EVENT_LEN: usize;
+    /// The same for the response. None if the event is never answered, which leaves
+    /// [`EventResponse::RESPONSE_ID`] free for other commands
+    const RESPONSE_LEN: Option<usize>;
+
+    /// The payload length of packets with `prefix`, or None if `prefix` isn't this command's
+    fn payload_len(prefix: u8) -> Option<usize> {
+        if prefix == Self::EVENT_ID {
+            Some(Self::EVENT_LEN)
+        } else if prefix == Self::RESPONSE_ID {
+            Self::RESPONSE_LEN
+        } else {
+            None
+        }
+    }
 
     fn read_event_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self>
     where
@@ -114,46 +605,30 @@ pub trait EventResponse: CommandId + Writeable {
         Self::Response: Sized;
 }
 
-#[derive(Debug, Clone)]
-pub enum BaseCommand {
-    ChangeHeight(Command<ChangeHeight>),
-    ReportHeight(Command<ReportHeight>),
-    ReportControllerState(Command<ControllerState>),
-    Connect(Command<Connect>),
-    // controller: 0x15, desk: 0x16
-    // 0x15 is a request for information it seems. The desk responds with 0x16 and the matching command id and 2 bytes of data
-    HandShake(Command<Handshake>),
-    // 0x13, 24 bit identiier
-    Identify(Command<Id>),
-}
-impl From<u8> for BaseCommand {
-    fn from(value: u8) -> Self {
-        match value {
-            ChangeHeight::EVENT_ID => BaseCommand::ChangeHeight(Command::Command(
-                ChangeHeight::Up(ChangeHeightState::Start),
-            )),
-            ChangeHeight::RESPONSE_ID => {
-                BaseCommand::ChangeHeight(Command::Reponse(ChangeHeight::SavedOne))
-            }
-            Connect::EVENT_ID => BaseCommand::Connect(Command::Command(Connect { state: () })),
-            Connect::RESPONSE_ID => {
-                BaseCommand::Connect(Command::Reponse(Connect::<bool> { state: true }))
-            }
-            // Add more cases here as needed.
-            _ => todo!(),
-        }
+base_command! {
+    /// Every packet, decoded by its prefix into the command it belongs to
+    ///
+    /// A command is registered here with one line. [`BaseCommand::decode`] and
+    /// [`BaseCommand::expected_payload_len`] are generated from its [`EventResponse`] impl.
+    #[derive(Debug, Clone)]
+    pub enum BaseCommand {
+        ChangeHeight(ChangeHeight),
+        ReportHeight(ReportHeight),
+        ReportControllerState(ControllerState),
+        Connect(Connect),
+        // controller: 0x15, desk: 0x16
+        // 0x15 is a request for information it seems. The desk responds with 0x16 and the matching command id and 2 bytes of data
+        HandShake(Register),
+        // 0x13, 24 bit identiier
+        Identify(DeviceIdentity),
+        MoveFinished(MoveFinished),
     }
 }
-impl Writeable for BaseCommand {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
-        match self {
-            BaseCommand::ChangeHeight(command) => command.write_to(writer),
-            BaseCommand::ReportHeight(command) => command.write_to(writer),
-            BaseCommand::ReportControllerState(command) => command.write_to(writer),
-            BaseCommand::Connect(command) => command.write_to(writer),
-            BaseCommand::HandShake(command) => command.write_to(writer),
-            BaseCommand::Identify(command) => command.write_to(writer),
-        }
+impl<'a> TryFrom<&'a Packet<'a>> for BaseCommand {
+    type Error = ProtocolError;
+
+    fn try_from(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
+        Self::decode(packet)
     }
 }
 
@@ -162,8 +637,23 @@ pub enum Command<C: EventResponse> {
     Command(C),
     Reponse(C::Response),
 }
+impl<C: EventResponse> Command<C> {
+    /// Reads either the event or the response of `C` depending on the prefix of the packet
+    pub fn read_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
+        match packet.get_command_prefix() {
+            prefix if prefix == C::EVENT_ID => Ok(Command::Command(C::read_event_from(packet)?)),
+            prefix if prefix == C::RESPONSE_ID => {
+                Ok(Command::Reponse(C::read_response_from(packet)?))
+            }
+            prefix => Err(ProtocolError::UnrecognizedCommand {
+                prefix,
+                packet: PacketBytes::new(packet.as_bytes()),
+            }),
+        }
+    }
+}
 impl<C: EventResponse> Writeable for Command<C> {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
         match self {
             Command::Command(c) => {
                 writer.write_all(&[C::EVENT_ID])?;
@@ -177,66 +667,191 @@ impl<C: EventResponse> Writeable for Command<C> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChangeHeight<S = ChangeHeightState> {
     Up(S),
     Down(S),
-    SavedOne,
-    SavedTwo,
-    SavedThree,
+    SavedOne(S),
+    SavedTwo(S),
+    SavedThree(S),
+    /// 0x10, which the desk acks like any other key. The only capture of it is a release
+    /// (`17 10 00`) as the keypad powers up, which is more likely a release of every key. It may
+    /// be the M key the keypad's manual saves presets with, see [`Preset::experimental_save`]
+    ///
+    /// [`Preset::experimental_save`]: crate::preset::Preset::experimental_save
+    Key10(S),
+}
+impl<S> ChangeHeight<S> {
+    fn from_command_id(command_id: u8, state: S, packet: &Packet<'_>) -> ProtocolResult<Self> {
+        Ok(match command_id {
+            0x03 => ChangeHeight::Up(state),
+            0x04 => ChangeHeight::Down(state),
+            0x06 => ChangeHeight::SavedOne(state),
+            0x07 => ChangeHeight::SavedTwo(state),
+            0x08 => ChangeHeight::SavedThree(state),
+            0x10 => ChangeHeight::Key10(state),
+            command_id => {
+                return Err(ProtocolError::UnrecognizedChangeHeightCommand {
+                    command_id,
+                    packet: PacketBytes::new(packet.as_bytes()),
+                })
+            }
+        })
+    }
+
+    pub fn state(&self) -> &S {
+        match self {
+            ChangeHeight::Up(state)
+            | ChangeHeight::Down(state)
+            | ChangeHeight::SavedOne(state)
+            | ChangeHeight::SavedTwo(state)
+            | ChangeHeight::SavedThree(state)
+            | ChangeHeight::Key10(state) => state,
+        }
+    }
+
+    /// The same key with a different state
+    pub fn with_state<T>(&self, state: T) -> ChangeHeight<T> {
+        match self {
+            ChangeHeight::Up(_) => ChangeHeight::Up(state),
+            ChangeHeight::Down(_) => ChangeHeight::Down(state),
+            ChangeHeight::SavedOne(_) => ChangeHeight::SavedOne(state),
+            ChangeHeight::SavedTwo(_) => ChangeHeight::SavedTwo(state),
+            ChangeHeight::SavedThree(_) => ChangeHeight::SavedThree(state),
+            ChangeHeight::Key10(_) => ChangeHeight::Key10(state),
+        }
+    }
 }
 impl<S> CommandId for ChangeHeight<S> {
     fn command_id(&self) -> u8 {
         match self {
             ChangeHeight::Up(_) => 0x03,
             ChangeHeight::Down(_) => 0x04,
-            ChangeHeight::SavedOne => 0x06,
-            ChangeHeight::SavedTwo => 0x07,
-            ChangeHeight::SavedThree => 0x08,
+            ChangeHeight::SavedOne(_) => 0x06,
+            ChangeHeight::SavedTwo(_) => 0x07,
+            ChangeHeight::SavedThree(_) => 0x08,
+            ChangeHeight::Key10(_) => 0x10,
         }
     }
 }
 impl EventResponse for ChangeHeight<ChangeHeightState> {
-    type Response = ChangeHeight<()>;
+    type Response = ChangeHeight<ChangeHeightResponse>;
     const EVENT_ID: u8 = 0x17;
+    const EVENT_LEN: usize = 1;
+    const RESPONSE_LEN: Option<usize> = Some(2);
 
     fn read_event_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
-        // match packet.get_command_id() {
-        //     0x03 =>
-        // }
-        todo!()
+        let [state] = packet.get_data_array()?;
+        let state = ChangeHeightState::try_from(state).map_err(|error| error.in_packet(packet))?;
+        Self::from_command_id(packet.get_command_id(), state, packet)
     }
 
     fn read_response_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self::Response>
     where
         Self::Response: Sized,
     {
-        todo!()
+        let [state, response] = packet.get_data_array()?;
+        let response = ChangeHeightResponse {
+            state: ChangeHeightState::try_from(state).map_err(|error| error.in_packet(packet))?,
+            response: ResponseState::try_from(response).map_err(|error| error.in_packet(packet))?,
+        };
+        ChangeHeight::from_command_id(packet.get_command_id(), response, packet)
     }
 }
 impl<S: Writeable> Writeable for ChangeHeight<S> {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
         writer.write_all(&[self.command_id()])?;
         match self {
-            ChangeHeight::Up(state) | ChangeHeight::Down(state) => state.write_to(writer),
-            ChangeHeight::SavedOne | ChangeHeight::SavedTwo | ChangeHeight::SavedThree => Ok(()),
+            ChangeHeight::Up(state)
+            | ChangeHeight::Down(state)
+            | ChangeHeight::SavedOne(state)
+            | ChangeHeight::SavedTwo(state)
+            | ChangeHeight::SavedThree(state)
+            | ChangeHeight::Key10(state) => state.write_to(writer),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChangeHeightState {
     Stop = 0,
     Start = 1,
 }
+impl TryFrom<u8> for ChangeHeightState {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> ProtocolResult<Self> {
+        match value {
+            0 => Ok(ChangeHeightState::Stop),
+            1 => Ok(ChangeHeightState::Start),
+            state => Err(ProtocolError::UnrecognizedMoveState {
+                state,
+                packet: PacketBytes::new(&[state]),
+            }),
+        }
+    }
+}
 impl Writeable for ChangeHeightState {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
         writer.write_all(&[*self as u8])
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct ReportHeight(f32);
+/// The desk echoes the key state back followed by a response state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeHeightResponse {
+    pub state: ChangeHeightState,
+    pub response: ResponseState,
+}
+impl Writeable for ChangeHeightResponse {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer.write_all(&[self.state as u8, self.response as u8])
+    }
+}
+
+/// The last payload byte of a [`ChangeHeightResponse`]
+///
+/// Every key press in the captures was answered with 0, on the way down and on the way up, so
+/// that is the only state known. Anything else is rejected rather than guessed at, it may be how
+/// the desk refuses a move
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseState {
+    Ok = 0,
+}
+impl TryFrom<u8> for ResponseState {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> ProtocolResult<Self> {
+        match value {
+            0 => Ok(ResponseState::Ok),
+            state => Err(ProtocolError::UnrecognizedResponseState {
+                state,
+                packet: PacketBytes::new(&[state]),
+            }),
+        }
+    }
+}
+
+/// The desk's height, sent about every 100ms while the link is up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportHeight {
+    /// The first payload byte. It has always been [`ReportHeight::STATE`] in the captures so
+    /// nobody knows what else it can be, but whatever was sent is kept
+    pub state: u8,
+    pub height: Height,
+}
+impl ReportHeight {
+    /// The state byte in every captured report
+    pub const STATE: u8 = 0x01;
+
+    /// A report with the captured state byte
+    pub const fn new(height: Height) -> Self {
+        Self {
+            state: Self::STATE,
+            height,
+        }
+    }
+}
 impl CommandId for ReportHeight {
     fn command_id(&self) -> u8 {
         0x00
@@ -245,158 +860,932 @@ impl CommandId for ReportHeight {
 impl EventResponse for ReportHeight {
     type Response = ();
     const EVENT_ID: u8 = 0x03;
+    const EVENT_LEN: usize = 3;
+    const RESPONSE_LEN: Option<usize> = None;
 
     fn read_event_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
-        todo!()
+        // would handle other command id's here but only know of one so no need to do anything with it for now
+        let command_id = packet.get_command_id();
+        if command_id != 0x00 {
+            return Err(ProtocolError::UnrecognizedReportHeightCommand {
+                command_id,
+                packet: PacketBytes::new(packet.as_bytes()),
+            });
+        }
+        let [state, high, low] = packet.get_data_array()?;
+        Ok(Self {
+            state,
+            height: Height::from_mm(u16::from_be_bytes([high, low])),
+        })
     }
 
     fn read_response_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self::Response> {
-        todo!()
+        // The desk never gets a response to a height report
+        Err(ProtocolError::UnrecognizedCommand {
+            prefix: packet.get_command_prefix(),
+            packet: PacketBytes::new(packet.as_bytes()),
+        })
     }
 }
 impl Writeable for ReportHeight {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
-        let height = (self.0 * 10.) as u16;
-        let height = height.to_be_bytes();
-        writer.write_all(&[height[0], height[1]])
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer.write_all(&[self.command_id(), self.state])?;
+        self.height.write_to(writer)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Connect<S = ()> {
-    state: S,
-}
-impl<S> CommandId for Connect<S> {
-    fn command_id(&self) -> u8 {
-        0x11
+command! {
+    prefix: 0x11,
+    command_id: 0x01,
+    /// The keypad asking to start the link. Sent until the desk answers
+    event Connect {}
+    response ConnectResponse {
+        /// Always true in the captures
+        connected: bool,
     }
 }
-impl EventResponse for Connect<()> {
-    type Response = Connect<bool>;
-    const EVENT_ID: u8 = 0x11;
 
-    fn read_event_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
-        todo!()
-    }
-
-    fn read_response_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self::Response> {
-        todo!()
-    }
+command! {
+    prefix: 0xA0,
+    command_id: 0x00,
+    /// The desk has stopped moving, either after the key was released or at a preset
+    event MoveFinished {}
+    /// The keypad's ack of a [`MoveFinished`]
+    response MoveFinishedAck {}
 }
-impl<S: Writeable> Writeable for Connect<S> {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
-        writer.write_all(&[self.command_id()])?;
-        self.state.write_to(writer)
+
+/// The heartbeat the keypad sends about every 200ms
+///
+/// The command id and the single payload byte together make a 16 bit status word. It has been
+/// 0xA004 in every capture, including while keys were held, so held keys come from the 0x17
+/// packets instead.
+///
+/// None of the bits are known. Without a capture where the word changes there is nothing to name
+/// them after, so all 16 bits are kept as they were sent and
+/// [`ControllerState::unexpected_bits`] gives the ones that differ from [`ControllerState::OK`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerState(pub u16);
+impl ControllerState {
+    pub const OK: Self = Self(0xA004);
+
+    pub fn is_ok(&self) -> bool {
+        *self == Self::OK
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub enum ControllerState {
-    Ok = 0xA004,
+    /// The bits that aren't the same as in [`ControllerState::OK`]. 0 when the state is ok
+    pub fn unexpected_bits(&self) -> u16 {
+        self.0 ^ Self::OK.0
+    }
 }
 impl CommandId for ControllerState {
     fn command_id(&self) -> u8 {
-        0xA0
+        self.0.to_be_bytes()[0]
     }
 }
 impl EventResponse for ControllerState {
     type Response = ();
     const EVENT_ID: u8 = 0x01;
+    const EVENT_LEN: usize = 1;
+    const RESPONSE_LEN: Option<usize> = None;
 
     fn read_event_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
-        todo!()
+        let [state] = packet.get_data_array()?;
+        Ok(ControllerState(u16::from_be_bytes([
+            packet.get_command_id(),
+            state,
+        ])))
     }
 
     fn read_response_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self::Response> {
-        todo!()
+        // The controller state is never responded to
+        Err(ProtocolError::UnrecognizedCommand {
+            prefix: packet.get_command_prefix(),
+            packet: PacketBytes::new(packet.as_bytes()),
+        })
     }
 }
 impl Writeable for ControllerState {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
-        writer.write_all(&[self.command_id()])?;
-        let bytes = (*self as u16).to_be_bytes();
-        writer.write_all(&[bytes[0], bytes[1]])
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        // The command id is the high byte of the state
+        writer.write_all(&self.0.to_be_bytes())
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Id<D = u32> {
-    commmand_id: u8,
-    data: D,
+/// What a device says it is when it identifies itself. Sent as the command id of 0x13 and 0x14
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceRole {
+    /// The desk's controller board
+    Desk,
+    Keypad,
+    Other(u8),
+}
+impl DeviceRole {
+    /// Which side of the link a device with this role sends from
+    pub fn source(&self) -> Option<Source> {
+        match self {
+            DeviceRole::Desk => Some(Source::Desk),
+            DeviceRole::Keypad => Some(Source::Controller),
+            DeviceRole::Other(_) => None,
+        }
+    }
+}
+impl From<u8> for DeviceRole {
+    fn from(value: u8) -> Self {
+        match value {
+            0x01 => DeviceRole::Desk,
+            0x03 => DeviceRole::Keypad,
+            value => DeviceRole::Other(value),
+        }
+    }
+}
+impl From<DeviceRole> for u8 {
+    fn from(value: DeviceRole) -> Self {
+        match value {
+            DeviceRole::Desk => 0x01,
+            DeviceRole::Keypad => 0x03,
+            DeviceRole::Other(value) => value,
+        }
+    }
+}
+
+/// A device introducing itself with 0x13
+///
+/// Both sides identify themselves when the link comes up, the keypad first. The captured keypad
+/// sends `03 FF 00 64` and the captured desk sends `01 FF 03 E8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub role: DeviceRole,
+    /// 0xFF from both captured devices
+    pub family: u8,
+    /// 100 from the captured keypad and 1000 from the captured desk. Presumably a firmware or
+    /// protocol version
+    pub version: u16,
+}
+impl DeviceIdentity {
+    /// What the captured keypad sends
+    pub const KEYPAD: Self = Self {
+        role: DeviceRole::Keypad,
+        family: 0xFF,
+        version: 100,
+    };
+    /// What the captured desk sends
+    pub const DESK: Self = Self {
+        role: DeviceRole::Desk,
+        family: 0xFF,
+        version: 1000,
+    };
+
+    /// The 24 bit identifier as it is sent on the wire
+    pub fn identifier(&self) -> u32 {
+        let [high, low] = self.version.to_be_bytes();
+        u32::from_be_bytes([0, self.family, high, low])
+    }
+
+    /// The reply the other side sends to this identity
+    pub fn ack(&self) -> IdentityAck {
+        IdentityAck {
+            role: self.role,
+            status: IdentityAck::OK,
+        }
+    }
+}
+impl core::fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.role {
+            DeviceRole::Desk => write!(f, "desk")?,
+            DeviceRole::Keypad => write!(f, "keypad")?,
+            DeviceRole::Other(role) => write!(f, "device {role:#04x}")?,
+        }
+        write!(f, " {:06X}", self.identifier())
+    }
 }
-impl<D> CommandId for Id<D> {
+impl CommandId for DeviceIdentity {
     fn command_id(&self) -> u8 {
-        self.commmand_id
+        self.role.into()
     }
 }
-impl EventResponse for Id<u32> {
-    type Response = Id<u16>;
+impl EventResponse for DeviceIdentity {
+    type Response = IdentityAck;
     const EVENT_ID: u8 = 0x13;
+    const EVENT_LEN: usize = 3;
+    const RESPONSE_LEN: Option<usize> = Some(2);
 
     fn read_event_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
-        todo!()
+        let [family, high, low] = packet.get_data_array()?;
+        Ok(DeviceIdentity {
+            role: packet.get_command_id().into(),
+            family,
+            version: u16::from_be_bytes([high, low]),
+        })
     }
 
     fn read_response_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self::Response> {
-        todo!()
+        Ok(IdentityAck {
+            role: packet.get_command_id().into(),
+            status: u16::from_be_bytes(packet.get_data_array()?),
+        })
     }
 }
-impl<D: Writeable> Writeable for Id<D> {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
-        writer.write_all(&[self.commmand_id])?;
-        self.data.write_to(writer)
+impl Writeable for DeviceIdentity {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer.write_all(&[self.command_id(), self.family])?;
+        self.version.write_to(writer)
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum Handshake<D = ()> {
-    Thirteen(D),
-    Fourteen(D),
-    Fifteen(D),
-    TwentyOne(D),
-    TwentyTwo(D),
-    TwentyThree(D),
-    SeventyTwo(D),
-    SeventyThree(D),
-}
-impl<D> CommandId for Handshake<D> {
+/// The reply to a [`DeviceIdentity`], sent with 0x14 and the role of the device being answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdentityAck {
+    pub role: DeviceRole,
+    pub status: u16,
+}
+impl IdentityAck {
+    /// The only status either captured device has sent
+    pub const OK: u16 = 0xFF00;
+}
+impl CommandId for IdentityAck {
     fn command_id(&self) -> u8 {
-        match self {
-            Handshake::Thirteen(_) => 0x13,
-            Handshake::Fourteen(_) => 0x14,
-            Handshake::Fifteen(_) => 0x15,
-            Handshake::TwentyOne(_) => 0x32,
-            Handshake::TwentyTwo(_) => 0x21,
-            Handshake::TwentyThree(_) => 0x23,
-            Handshake::SeventyTwo(_) => 0x72,
-            Handshake::SeventyThree(_) => 0x73,
-        }
+        self.role.into()
+    }
+}
+impl Writeable for IdentityAck {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer.write_all(&[self.command_id()])?;
+        self.status.write_to(writer)
+    }
+}
+
+/// A desk configuration register the keypad reads during the handshake
+///
+/// The keypad asks for each register with 0x15 and the desk answers with 0x16, the same register
+/// and a 2 byte value. The names come from the values the captured desk answers with.
+///
+/// The keypad asks for 0x13, 0x14 and 0x15 before anything else and the captured desk answers
+/// 0x0001 to all three. With only one desk to compare against, what they mean is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Unknown13 = 0x13,
+    Unknown14 = 0x14,
+    Unknown15 = 0x15,
+    /// The lowest height the desk can move to
+    MinHeight = 0x21,
+    /// The highest height the desk can move to
+    MaxHeight = 0x22,
+    Units = 0x23,
+    /// The upper limit the user has set. The same as [`Register::MaxHeight`] when none is set
+    UserMaxHeight = 0x72,
+    /// The lower limit the user has set. The same as [`Register::MinHeight`] when none is set
+    UserMinHeight = 0x73,
+}
+impl Register {
+    /// Every register in the order the keypad asks for them
+    pub const ALL: [Register; 8] = [
+        Register::Unknown13,
+        Register::Unknown14,
+        Register::Unknown15,
+        Register::MinHeight,
+        Register::MaxHeight,
+        Register::Units,
+        Register::UserMaxHeight,
+        Register::UserMinHeight,
+    ];
+}
+impl TryFrom<u8> for Register {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> ProtocolResult<Self> {
+        Register::ALL
+            .into_iter()
+            .find(|register| *register as u8 == value)
+            .ok_or(ProtocolError::UnrecognizedRegister {
+                register: value,
+                packet: PacketBytes::new(&[value]),
+            })
+    }
+}
+impl CommandId for Register {
+    fn command_id(&self) -> u8 {
+        *self as u8
     }
 }
-impl EventResponse for Handshake<()> {
-    type Response = Handshake<u16>;
+impl EventResponse for Register {
+    type Response = RegisterValue;
     const EVENT_ID: u8 = 0x15;
+    const EVENT_LEN: usize = 0;
+    const RESPONSE_LEN: Option<usize> = Some(2);
 
     fn read_event_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self> {
-        todo!()
+        let [] = packet.get_data_array()?;
+        Register::try_from(packet.get_command_id()).map_err(|error| error.in_packet(packet))
     }
 
     fn read_response_from<'a>(packet: &'a Packet<'a>) -> ProtocolResult<Self::Response> {
-        todo!()
+        let register =
+            Register::try_from(packet.get_command_id()).map_err(|error| error.in_packet(packet))?;
+        let raw = u16::from_be_bytes(packet.get_data_array()?);
+        Ok(RegisterValue::from_raw(register, raw))
     }
 }
-impl<D: Writeable> Writeable for Handshake<D> {
-    fn write_to<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
-        writer.write_all(&[self.command_id()])?;
+impl Writeable for Register {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer.write_all(&[self.command_id()])
+    }
+}
+
+/// How the desk displays heights. 0 on the captured desk which shows centimetres
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    Centimetres,
+    /// Nothing else has been captured yet
+    Other(u16),
+}
+impl From<u16> for Units {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Units::Centimetres,
+            value => Units::Other(value),
+        }
+    }
+}
+impl From<Units> for u16 {
+    fn from(value: Units) -> Self {
+        match value {
+            Units::Centimetres => 0,
+            Units::Other(value) => value,
+        }
+    }
+}
+
+/// The value of a [`Register`] as the desk sent it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterValue {
+    Unknown13(u16),
+    Unknown14(u16),
+    Unknown15(u16),
+    MinHeight(Height),
+    MaxHeight(Height),
+    Units(Units),
+    UserMaxHeight(Height),
+    UserMinHeight(Height),
+}
+impl RegisterValue {
+    pub fn from_raw(register: Register, raw: u16) -> Self {
+        match register {
+            Register::Unknown13 => RegisterValue::Unknown13(raw),
+            Register::Unknown14 => RegisterValue::Unknown14(raw),
+            Register::Unknown15 => RegisterValue::Unknown15(raw),
+            Register::MinHeight => RegisterValue::MinHeight(Height::from_mm(raw)),
+            Register::MaxHeight => RegisterValue::MaxHeight(Height::from_mm(raw)),
+            Register::Units => RegisterValue::Units(raw.into()),
+            Register::UserMaxHeight => RegisterValue::UserMaxHeight(Height::from_mm(raw)),
+            Register::UserMinHeight => RegisterValue::UserMinHeight(Height::from_mm(raw)),
+        }
+    }
+
+    pub fn register(&self) -> Register {
         match self {
-            Handshake::Thirteen(data)
-            | Handshake::Fourteen(data)
-            | Handshake::Fifteen(data)
-            | Handshake::TwentyOne(data)
-            | Handshake::TwentyTwo(data)
-            | Handshake::TwentyThree(data)
-            | Handshake::SeventyTwo(data)
-            | Handshake::SeventyThree(data) => data.write_to(writer),
+            RegisterValue::Unknown13(_) => Register::Unknown13,
+            RegisterValue::Unknown14(_) => Register::Unknown14,
+            RegisterValue::Unknown15(_) => Register::Unknown15,
+            RegisterValue::MinHeight(_) => Register::MinHeight,
+            RegisterValue::MaxHeight(_) => Register::MaxHeight,
+            RegisterValue::Units(_) => Register::Units,
+            RegisterValue::UserMaxHeight(_) => Register::UserMaxHeight,
+            RegisterValue::UserMinHeight(_) => Register::UserMinHeight,
+        }
+    }
+
+    pub fn raw(&self) -> u16 {
+        match *self {
+            RegisterValue::Unknown13(raw)
+            | RegisterValue::Unknown14(raw)
+            | RegisterValue::Unknown15(raw) => raw,
+            RegisterValue::MinHeight(height)
+            | RegisterValue::MaxHeight(height)
+            | RegisterValue::UserMaxHeight(height)
+            | RegisterValue::UserMinHeight(height) => height.to_mm(),
+            RegisterValue::Units(units) => units.into(),
+        }
+    }
+}
+impl CommandId for RegisterValue {
+    fn command_id(&self) -> u8 {
+        self.register().command_id()
+    }
+}
+impl Writeable for RegisterValue {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer.write_all(&[self.command_id()])?;
+        self.raw().write_to(writer)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        config::DeskConfigBuilder,
+        encoder::{encode, encode_packet},
+        framer::Framer,
+        height::HeightLimits,
+    };
+
+    /// Collects encoded packets
+    pub(crate) struct Wire(pub Vec<u8>);
+    impl Write for Wire {
+        type Error = core::convert::Infallible;
+
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            self.0.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    /// `command` as it goes out on the wire
+    pub(crate) fn wire<C: Writeable>(command: &C, packet_num: u16) -> Vec<u8> {
+        let mut wire = Wire(Vec::new());
+        encode(command, packet_num, &mut wire).unwrap();
+        wire.0
+    }
+
+    /// The unescaped packet for a body of prefix, command id and payload
+    pub(crate) fn packet(body: &[u8], packet_num: u16) -> PacketBuf {
+        let command = BaseCommand::Unknown {
+            prefix: body[0],
+            command_id: body[1],
+            payload: Payload::new(&body[2..]).unwrap(),
+        };
+        encode_packet(&command, packet_num).unwrap()
+    }
+
+    /// Frames and decodes a single packet as it was captured
+    pub(crate) fn decode(wire: &[u8]) -> (BaseCommand, u16) {
+        let mut framer = Framer::<MAX_PACKET_LEN>::new();
+        let (last, bytes) = wire.split_last().unwrap();
+        for &byte in bytes {
+            assert!(framer.push(byte).unwrap().is_none());
+        }
+        let packet = framer.push(*last).unwrap().unwrap();
+        (
+            BaseCommand::decode(&packet).unwrap(),
+            packet.get_packet_num(),
+        )
+    }
+
+    fn decode_body(body: &[u8]) -> ProtocolResult<BaseCommand> {
+        BaseCommand::decode(&packet(body, 1).as_packet())
+    }
+
+    /// One of every kind of packet in `data-captures/data`
+    const CAPTURED: &[&[u8]] = &[
+        &[0xFA, 0x17, 0x03, 0x01, 0x06, 0xCF, 0xDC, 0xFD],
+        &[0xFA, 0x17, 0x10, 0x00, 0x01, 0x39, 0x3F, 0xFD],
+        &[0xFA, 0x18, 0x03, 0x01, 0x00, 0x0D, 0x1E, 0x09, 0xFD],
+        &[0xFA, 0x18, 0x10, 0x00, 0x00, 0x02, 0x65, 0x6F, 0xFD],
+        &[0xFA, 0x03, 0x00, 0x01, 0x02, 0xD4, 0x17, 0x9E, 0x5D, 0xFD],
+        &[
+            0xFA, 0x03, 0x00, 0x01, 0x02, 0xFE, 0xFA, 0x0D, 0x31, 0xC6, 0xFD,
+        ],
+        &[
+            0xFA, 0x03, 0x00, 0x01, 0x02, 0xFE, 0xFE, 0x09, 0xB3, 0x44, 0xFD,
+        ],
+        &[0xFA, 0x01, 0xA0, 0x04, 0x00, 0x0D, 0xA8, 0xFD],
+        &[0xFA, 0x01, 0xA0, 0x04, 0x04, 0xFE, 0xFE, 0x5F, 0xFD],
+        &[0xFA, 0x01, 0xA0, 0x04, 0x01, 0x59, 0xFE, 0xFD, 0xFD],
+        &[0xFA, 0x11, 0x01, 0x01, 0x3B, 0x2A, 0xFD],
+        &[0xFA, 0x12, 0x01, 0x01, 0x17, 0x93, 0x96, 0xFD],
+        &[0xFA, 0x13, 0x03, 0xFF, 0x00, 0x64, 0x00, 0x03, 0x88, 0xFD],
+        &[0xFA, 0x13, 0x01, 0xFF, 0x03, 0xE8, 0x17, 0x95, 0x84, 0xFD],
+        &[0xFA, 0x14, 0x03, 0xFF, 0x00, 0x17, 0x94, 0x6B, 0xFD],
+        &[0xFA, 0x14, 0x01, 0xFF, 0x00, 0x00, 0x0C, 0xE6, 0xFD],
+        &[0xFA, 0x15, 0x13, 0x00, 0x04, 0x02, 0xFD],
+        &[0xFA, 0x15, 0x73, 0x00, 0x0B, 0x6D, 0xFD],
+        &[0xFA, 0x16, 0x13, 0x00, 0x01, 0x17, 0x96, 0x85, 0xFD],
+        &[0xFA, 0x16, 0x21, 0x02, 0x8A, 0x17, 0x99, 0x31, 0xFD],
+        &[0xFA, 0x16, 0x22, 0x04, 0xE2, 0x17, 0x9A, 0x5F, 0xFD],
+        &[0xFA, 0x16, 0x23, 0x00, 0x00, 0x17, 0x9B, 0xB9, 0xFD],
+        &[0xFA, 0x16, 0x72, 0x04, 0xE2, 0x17, 0x9C, 0x09, 0xFD],
+        &[0xFA, 0x16, 0x73, 0x02, 0x8A, 0x17, 0x9D, 0x67, 0xFD],
+        &[0xFA, 0xA0, 0x00, 0x02, 0xAA, 0x08, 0xFD],
+        &[0xFA, 0xA1, 0x00, 0x06, 0xDF, 0x78, 0xFD],
+    ];
+
+    #[test]
+    fn captured_packets_round_trip() {
+        for captured in CAPTURED {
+            let (command, packet_num) = decode(captured);
+            assert!(
+                !matches!(command, BaseCommand::Unknown { .. }),
+                "{captured:02X?} decoded as {command:?}"
+            );
+            assert_eq!(wire(&command, packet_num), *captured, "{command:?}");
         }
     }
+
+    #[test]
+    fn captured_payload_lengths() {
+        for captured in CAPTURED {
+            let (command, _) = decode(captured);
+            if matches!(command, BaseCommand::Unknown { .. }) {
+                continue;
+            }
+            let mut body = Wire(Vec::new());
+            command.write_to(&mut body).unwrap();
+            assert_eq!(
+                BaseCommand::expected_payload_len(body.0[0]),
+                Some(body.0.len() - 2),
+                "{captured:02X?}"
+            );
+        }
+        // Height reports and heartbeats are never answered, so the prefix after them is unknown
+        assert_eq!(BaseCommand::expected_payload_len(0x04), None);
+        assert_eq!(BaseCommand::expected_payload_len(0x02), None);
+        assert!(matches!(
+            decode_body(&[0x04, 0x00]).unwrap(),
+            BaseCommand::Unknown { prefix: 0x04, .. }
+        ));
+    }
+
+    #[test]
+    fn decodes_key_press() {
+        let (command, packet_num) = decode(CAPTURED[0]);
+        assert!(matches!(
+            command,
+            BaseCommand::ChangeHeight(Command::Command(ChangeHeight::Up(ChangeHeightState::Start)))
+        ));
+        assert_eq!(packet_num, 0x06CF);
+    }
+
+    #[test]
+    fn decodes_key_ack() {
+        let (command, _) = decode(CAPTURED[2]);
+        let BaseCommand::ChangeHeight(Command::Reponse(ChangeHeight::Up(response))) = command
+        else {
+            panic!("{command:?}");
+        };
+        assert_eq!(response.state, ChangeHeightState::Start);
+        assert_eq!(response.response, ResponseState::Ok);
+    }
+
+    #[test]
+    fn decodes_escaped_height() {
+        let (command, packet_num) = decode(CAPTURED[5]);
+        let BaseCommand::ReportHeight(Command::Command(report)) = command else {
+            panic!("{command:?}");
+        };
+        assert_eq!(report.state, ReportHeight::STATE);
+        assert_eq!(report.height, Height::from_mm(0x02FA));
+        assert_eq!(packet_num, 0x0D31);
+    }
+
+    #[test]
+    fn report_height_keeps_state() {
+        let command = decode_body(&[0x03, 0x00, 0x02, 0x02, 0xD4]).unwrap();
+        let BaseCommand::ReportHeight(Command::Command(report)) = command else {
+            panic!("{command:?}");
+        };
+        assert_eq!(report.state, 0x02);
+        assert_eq!(
+            encode_packet(&command, 1).unwrap(),
+            packet(&[0x03, 0x00, 0x02, 0x02, 0xD4], 1)
+        );
+    }
+
+    #[test]
+    fn decodes_heartbeat() {
+        let (command, packet_num) = decode(CAPTURED[9]);
+        let BaseCommand::ReportControllerState(Command::Command(state)) = command else {
+            panic!("{command:?}");
+        };
+        assert!(state.is_ok());
+        assert_eq!(state.unexpected_bits(), 0);
+        assert_eq!(packet_num, 0x0159);
+    }
+
+    #[test]
+    fn heartbeat_keeps_unknown_bits() {
+        let command = decode_body(&[0x01, 0xA0, 0x05]).unwrap();
+        let BaseCommand::ReportControllerState(Command::Command(state)) = command else {
+            panic!("{command:?}");
+        };
+        assert_eq!(state, ControllerState(0xA005));
+        assert!(!state.is_ok());
+        assert_eq!(state.unexpected_bits(), 0x0001);
+        assert_eq!(
+            encode_packet(&command, 1).unwrap(),
+            packet(&[0x01, 0xA0, 0x05], 1)
+        );
+    }
+
+    #[test]
+    fn decodes_connect() {
+        let (command, _) = decode(CAPTURED[10]);
+        assert!(matches!(
+            command,
+            BaseCommand::Connect(Command::Command(Connect {}))
+        ));
+        let (command, _) = decode(CAPTURED[11]);
+        assert!(matches!(
+            command,
+            BaseCommand::Connect(Command::Reponse(ConnectResponse { connected: true }))
+        ));
+    }
+
+    #[test]
+    fn decodes_identify() {
+        let (command, _) = decode(CAPTURED[12]);
+        assert!(
+            matches!(command, BaseCommand::Identify(Command::Command(identity)) if identity == DeviceIdentity::KEYPAD)
+        );
+        let (command, _) = decode(CAPTURED[13]);
+        assert!(
+            matches!(command, BaseCommand::Identify(Command::Command(identity)) if identity == DeviceIdentity::DESK)
+        );
+        let (command, _) = decode(CAPTURED[14]);
+        assert!(
+            matches!(command, BaseCommand::Identify(Command::Reponse(ack)) if ack == DeviceIdentity::KEYPAD.ack())
+        );
+    }
+
+    #[test]
+    fn decodes_registers() {
+        let (command, _) = decode(CAPTURED[16]);
+        assert!(matches!(
+            command,
+            BaseCommand::HandShake(Command::Command(Register::Unknown13))
+        ));
+        let expected = [
+            RegisterValue::Unknown13(1),
+            RegisterValue::MinHeight(Height::from_mm(650)),
+            RegisterValue::MaxHeight(Height::from_mm(1250)),
+            RegisterValue::Units(Units::Centimetres),
+            RegisterValue::UserMaxHeight(Height::from_mm(1250)),
+            RegisterValue::UserMinHeight(Height::from_mm(650)),
+        ];
+        for (captured, expected) in CAPTURED[18..24].iter().zip(expected) {
+            let (command, _) = decode(captured);
+            assert!(
+                matches!(command, BaseCommand::HandShake(Command::Reponse(value)) if value == expected),
+                "{command:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_move_finished() {
+        let (command, _) = decode(CAPTURED[24]);
+        assert!(matches!(
+            command,
+            BaseCommand::MoveFinished(Command::Command(MoveFinished {}))
+        ));
+        let (command, packet_num) = decode(CAPTURED[25]);
+        assert!(matches!(
+            command,
+            BaseCommand::MoveFinished(Command::Reponse(MoveFinishedAck {}))
+        ));
+        assert_eq!(packet_num, 0x06DF);
+    }
+
+    #[test]
+    fn unknown_prefix_round_trips() {
+        // 0xA1 was the example of an unknown prefix until it was decoded as a MoveFinishedAck.
+        // 0x42 hasn't been seen at all
+        let body = [0x42, 0x07, 0x01, 0x02, 0x03];
+        let command = decode_body(&body).unwrap();
+        let BaseCommand::Unknown {
+            prefix,
+            command_id,
+            payload,
+        } = command
+        else {
+            panic!("{command:?}");
+        };
+        assert_eq!((prefix, command_id), (0x42, 0x07));
+        assert_eq!(payload.as_bytes(), &body[2..]);
+        assert_eq!(encode_packet(&command, 1).unwrap(), packet(&body, 1));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut bytes = CAPTURED[0].to_vec();
+        bytes[6] = 0xDD;
+        let error = BaseCommand::decode(&Packet::new(&bytes).unwrap()).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::BadCheckSum {
+                expected: 0xDC,
+                actual: 0xDD,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let error = Packet::new(&[0xFA, 0x17, 0x03, 0xFD]).unwrap_err();
+        assert!(matches!(error, ProtocolError::PacketTooShort(_)));
+        let error = Packet::new(&[0xFA]).unwrap_err();
+        assert!(matches!(error, ProtocolError::PacketTooShort(_)));
+        let error = Packet::new(&CAPTURED[0][1..]).unwrap_err();
+        assert!(matches!(error, ProtocolError::MissingStartTag(_)));
+        let error = Packet::new(&CAPTURED[0][..7]).unwrap_err();
+        assert!(matches!(error, ProtocolError::MissingEndTag(_)));
+        let error = PacketBuf::<8>::new(CAPTURED[2]).unwrap_err();
+        assert!(matches!(error, ProtocolError::PacketTooLong(_)));
+        let error = Payload::new(&[0; MAX_PAYLOAD_LEN + 1]).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::PayloadTooLong { len: 10, .. }
+        ));
+    }
+
+    #[test]
+    fn unexpected_payload_length() {
+        let error = decode_body(&[0x17, 0x03, 0x01, 0x00]).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::UnexpectedPayloadLength {
+                expected: 1,
+                actual: 2,
+                ..
+            }
+        ));
+        let error = decode_body(&[0x12, 0x01]).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::UnexpectedPayloadLength {
+                expected: 1,
+                actual: 0,
+                ..
+            }
+        ));
+    }
+
+    /// Decodes `body` expecting an error that kept the whole packet
+    fn decode_error(body: &[u8]) -> ProtocolError {
+        let packet = packet(body, 1);
+        let error = BaseCommand::decode(&packet.as_packet()).unwrap_err();
+        let kept = match &error {
+            ProtocolError::UnrecognizedCommand { packet, .. }
+            | ProtocolError::UnrecognizedChangeHeightCommand { packet, .. }
+            | ProtocolError::UnrecognizedReportHeightCommand { packet, .. }
+            | ProtocolError::UnrecognizedMoveState { packet, .. }
+            | ProtocolError::UnrecognizedResponseState { packet, .. }
+            | ProtocolError::UnrecognizedRegister { packet, .. }
+            | ProtocolError::InvalidFlag { packet, .. } => packet,
+            error => panic!("{error:?}"),
+        };
+        assert_eq!(kept.as_bytes(), packet.as_bytes());
+        error
+    }
+
+    #[test]
+    fn unrecognized_values() {
+        let error = decode_error(&[0x17, 0x05, 0x01]);
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedChangeHeightCommand {
+                command_id: 0x05,
+                ..
+            }
+        ));
+        let error = decode_error(&[0x17, 0x03, 0x02]);
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedMoveState { state: 0x02, .. }
+        ));
+        let error = decode_error(&[0x18, 0x03, 0x02, 0x00]);
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedMoveState { state: 0x02, .. }
+        ));
+        let error = decode_error(&[0x18, 0x03, 0x01, 0x01]);
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedResponseState { state: 0x01, .. }
+        ));
+        let error = decode_error(&[0x03, 0x01, 0x01, 0x02, 0xD4]);
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedReportHeightCommand {
+                command_id: 0x01,
+                ..
+            }
+        ));
+        let error = decode_error(&[0x15, 0x30]);
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedRegister { register: 0x30, .. }
+        ));
+        let error = decode_error(&[0x16, 0x30, 0x00, 0x01]);
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedRegister { register: 0x30, .. }
+        ));
+        let error = decode_error(&[0x12, 0x01, 0x02]);
+        assert!(matches!(
+            error,
+            ProtocolError::InvalidFlag { flag: 0x02, .. }
+        ));
+    }
+
+    #[test]
+    fn unrecognized_command() {
+        // Neither the event nor the response of the command being read
+        let key = packet(&[0x17, 0x03, 0x01], 1);
+        let error = Command::<Connect>::read_from(&key.as_packet()).unwrap_err();
+        assert!(
+            matches!(error, ProtocolError::UnrecognizedCommand { prefix: 0x17, packet } if packet.as_bytes() == key.as_bytes())
+        );
+        // Height reports and heartbeats are never answered
+        let report = packet(&[0x04, 0x00], 1);
+        let error = Command::<ReportHeight>::read_from(&report.as_packet()).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedCommand { prefix: 0x04, .. }
+        ));
+        let heartbeat = packet(&[0x02, 0xA0, 0x04], 1);
+        let error = Command::<ControllerState>::read_from(&heartbeat.as_packet()).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedCommand { prefix: 0x02, .. }
+        ));
+    }
+
+    #[test]
+    fn values_outside_of_packets() {
+        let error = Register::try_from(0x30).unwrap_err();
+        assert!(
+            matches!(error, ProtocolError::UnrecognizedRegister { register: 0x30, packet } if packet.as_bytes() == [0x30])
+        );
+        let error = ChangeHeightState::try_from(0x02).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::UnrecognizedMoveState { state: 0x02, .. }
+        ));
+    }
+
+    #[test]
+    fn payload_fields_check_their_length() {
+        assert_eq!(u16::read(&[0x02, 0xD4]).unwrap(), 0x02D4);
+        assert_eq!(Height::read(&[0x02, 0xD4]).unwrap(), Height::from_mm(724));
+        assert!(bool::read(&[0x01]).unwrap());
+        for bytes in [&[][..], &[0x02], &[0x02, 0xD4, 0x00]] {
+            assert!(matches!(
+                u16::read(bytes),
+                Err(ProtocolError::UnexpectedPayloadLength { expected: 2, actual, packet })
+                    if actual == bytes.len() && packet.as_bytes() == bytes
+            ));
+            assert!(matches!(
+                Height::read(bytes),
+                Err(ProtocolError::UnexpectedPayloadLength { expected: 2, .. })
+            ));
+        }
+        for bytes in [&[][..], &[0x01, 0x01]] {
+            assert!(matches!(
+                u8::read(bytes),
+                Err(ProtocolError::UnexpectedPayloadLength { expected: 1, .. })
+            ));
+            assert!(matches!(
+                bool::read(bytes),
+                Err(ProtocolError::UnexpectedPayloadLength { expected: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn error_display() {
+        let error = decode_error(&[0x15, 0x30]);
+        assert_eq!(
+            std::format!("{error}"),
+            "unknown configuration register 0x30: FA 15 30 00 01 24 FD"
+        );
+        let error = Payload::new(&[0; MAX_PAYLOAD_LEN + 1]).unwrap_err();
+        assert_eq!(
+            std::format!("{error}"),
+            "payload is 10 bytes, longer than the maximum of 9: 00 00 00 00 00 00 00 00 00 00"
+        );
+    }
+
+    #[test]
+    fn invalid_heights() {
+        assert!(matches!(
+            Height::from_cm(-1.),
+            Err(ProtocolError::InvalidHeight(_))
+        ));
+        assert!(matches!(
+            Height::from_inches(f32::NAN),
+            Err(ProtocolError::InvalidHeight(_))
+        ));
+        assert!(matches!(
+            HeightLimits::DEFAULT.check(Height::from_mm(600)),
+            Err(ProtocolError::HeightOutOfRange { .. })
+        ));
+        assert!(matches!(
+            DeskConfigBuilder::new().build(),
+            Err(ProtocolError::MissingRegister(Register::Unknown13))
+        ));
+    }
 }
@@ -0,0 +1,243 @@
+//! Saving and recalling the desk's memory presets
+//!
+//! Recalling a preset is a tap of its key: the captures show the keypad sending
+//! [`ChangeHeightState::Start`] then [`ChangeHeightState::Stop`] about 75-100ms apart, after which
+//! the desk moves on its own and sends `A0 00` once it has arrived.
+//!
+//! Saving is experimental. The keypad's manual has it as "M + n", but no save has been captured.
+//! [`Preset::experimental_save`] guesses that M is [`ChangeHeight::Key10`], the only key id with no
+//! known key, since the desk acks it like the others. Check the desk with a capture before relying
+//! on it.
+//!
+//! The desk has no register for the stored heights, so [`PresetTracker`] learns them by watching
+//! the link: the height when a save is keyed in, or the height the desk stops at after a recall.
+
+use crate::{
+    height::Height,
+    new_protocol::{BaseCommand, ChangeHeight, ChangeHeightState, Command},
+};
+
+/// How long to hold a key for a tap, and how long to wait between taps
+pub const KEY_TAP_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    One,
+    Two,
+    Three,
+}
+impl Preset {
+    pub const ALL: [Preset; 3] = [Preset::One, Preset::Two, Preset::Three];
+
+    /// The key on the keypad for this preset
+    pub fn key<S>(&self, state: S) -> ChangeHeight<S> {
+        match self {
+            Preset::One => ChangeHeight::SavedOne(state),
+            Preset::Two => ChangeHeight::SavedTwo(state),
+            Preset::Three => ChangeHeight::SavedThree(state),
+        }
+    }
+
+    pub fn from_key<S>(key: &ChangeHeight<S>) -> Option<Self> {
+        match key {
+            ChangeHeight::SavedOne(_) => Some(Preset::One),
+            ChangeHeight::SavedTwo(_) => Some(Preset::Two),
+            ChangeHeight::SavedThree(_) => Some(Preset::Three),
+            ChangeHeight::Up(_) | ChangeHeight::Down(_) | ChangeHeight::Key10(_) => None,
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// The key events that move the desk to this preset. Send them [`KEY_TAP_MS`] apart
+    pub fn recall(&self) -> [ChangeHeight; 2] {
+        tap(self.key(()))
+    }
+
+    /// The key events that should store the desk's current height in this preset. Send them
+    /// [`KEY_TAP_MS`] apart
+    ///
+    /// # Experimental
+    ///
+    /// This is the manual's "M + n" with M guessed to be [`ChangeHeight::Key10`]. No save has
+    /// been captured, so the desk may ignore it or do something else entirely
+    pub fn experimental_save(&self) -> [ChangeHeight; 4] {
+        let [m_start, m_stop] = tap(ChangeHeight::Key10(()));
+        let [start, stop] = tap(self.key(()));
+        [m_start, m_stop, start, stop]
+    }
+}
+
+fn tap(key: ChangeHeight<()>) -> [ChangeHeight; 2] {
+    [
+        key.with_state(ChangeHeightState::Start),
+        key.with_state(ChangeHeightState::Stop),
+    ]
+}
+
+/// Learns the preset heights from the packets going both ways
+#[derive(Debug, Clone, Default)]
+pub struct PresetTracker {
+    heights: [Option<Height>; Preset::ALL.len()],
+    height: Option<Height>,
+    /// [`ChangeHeight::Key10`] has been tapped and the next preset key is taken as a save
+    saving: bool,
+    /// A preset has been recalled and the desk hasn't stopped yet
+    recalling: Option<Preset>,
+}
+impl PresetTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, command: &BaseCommand) {
+        match command {
+            BaseCommand::ReportHeight(Command::Command(report)) => {
+                self.height = Some(report.height)
+            }
+            BaseCommand::ChangeHeight(Command::Command(key))
+                if *key.state() == ChangeHeightState::Start =>
+            {
+                match (Preset::from_key(key), self.saving) {
+                    (Some(preset), true) => {
+                        self.saving = false;
+                        self.heights[preset.index()] = self.height;
+                    }
+                    // Any other key stops a recall
+                    (preset, _) => {
+                        self.saving = matches!(key, ChangeHeight::Key10(_));
+                        self.recalling = preset;
+                    }
+                }
+            }
+            BaseCommand::MoveFinished(Command::Command(_)) => {
+                if let Some(preset) = self.recalling.take() {
+                    self.heights[preset.index()] = self.height;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The height stored in `preset`, if it has been saved or recalled while watching
+    pub fn height(&self, preset: Preset) -> Option<Height> {
+        self.heights[preset.index()]
+    }
+
+    /// Records a height learned some other way, such as from an earlier session
+    pub fn set_height(&mut self, preset: Preset, height: Height) {
+        self.heights[preset.index()] = Some(height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_protocol::{tests::wire, MoveFinished, ReportHeight};
+    use std::vec::Vec;
+
+    fn key(key: ChangeHeight) -> BaseCommand {
+        BaseCommand::ChangeHeight(Command::Command(key))
+    }
+
+    fn finished() -> BaseCommand {
+        BaseCommand::MoveFinished(Command::Command(MoveFinished {}))
+    }
+
+    fn report(mm: u16) -> BaseCommand {
+        BaseCommand::ReportHeight(Command::Command(ReportHeight::new(Height::from_mm(mm))))
+    }
+
+    #[test]
+    fn recall_matches_capture() {
+        // The keypad recalling preset one in the root capture
+        let [start, stop] = Preset::One.recall();
+        assert_eq!(
+            wire(&Command::Command(start), 0x014D),
+            [0xFA, 0x17, 0x06, 0x01, 0x01, 0x4D, 0x5C, 0xFD]
+        );
+        assert_eq!(
+            wire(&Command::Command(stop), 0x014E),
+            [0xFA, 0x17, 0x06, 0x00, 0x01, 0x4E, 0x5E, 0xFD]
+        );
+    }
+
+    #[test]
+    fn experimental_save() {
+        let wires: Vec<_> = Preset::Three
+            .experimental_save()
+            .iter()
+            .zip(1..)
+            .map(|(key, packet_num)| wire(&Command::Command(*key), packet_num))
+            .collect();
+        assert_eq!(
+            wires,
+            [
+                wire(
+                    &Command::Command(ChangeHeight::Key10(ChangeHeightState::Start)),
+                    1
+                ),
+                wire(
+                    &Command::Command(ChangeHeight::Key10(ChangeHeightState::Stop)),
+                    2
+                ),
+                wire(
+                    &Command::Command(ChangeHeight::SavedThree(ChangeHeightState::Start)),
+                    3
+                ),
+                wire(
+                    &Command::Command(ChangeHeight::SavedThree(ChangeHeightState::Stop)),
+                    4
+                ),
+            ]
+        );
+        // The release of 0x10 is the packet the keypad sends as it powers up
+        assert_eq!(wires[1][1..4], [0x17, 0x10, 0x00]);
+    }
+
+    #[test]
+    fn learns_saved_height() {
+        let mut tracker = PresetTracker::new();
+        tracker.observe(&report(812));
+        for event in Preset::One.experimental_save() {
+            tracker.observe(&key(event));
+        }
+        assert_eq!(tracker.height(Preset::One), Some(Height::from_mm(812)));
+        // A save isn't a recall, so stopping later doesn't overwrite it
+        tracker.observe(&report(900));
+        tracker.observe(&finished());
+        assert_eq!(tracker.height(Preset::One), Some(Height::from_mm(812)));
+        // Another key between M and the preset key cancels the save
+        for event in [ChangeHeight::Key10(()), ChangeHeight::Up(())] {
+            tracker.observe(&key(event.with_state(ChangeHeightState::Start)));
+        }
+        tracker.observe(&key(ChangeHeight::SavedTwo(ChangeHeightState::Start)));
+        assert_eq!(tracker.height(Preset::Two), None);
+    }
+
+    #[test]
+    fn learns_recalled_height() {
+        let mut tracker = PresetTracker::new();
+        tracker.observe(&report(724));
+        for event in Preset::Two.recall() {
+            tracker.observe(&key(event));
+        }
+        tracker.observe(&report(900));
+        assert_eq!(tracker.height(Preset::Two), None);
+        tracker.observe(&finished());
+        assert_eq!(tracker.height(Preset::Two), Some(Height::from_mm(900)));
+        assert_eq!(tracker.height(Preset::One), None);
+    }
+
+    #[test]
+    fn other_key_stops_recall() {
+        let mut tracker = PresetTracker::new();
+        tracker.observe(&report(724));
+        tracker.observe(&key(ChangeHeight::SavedOne(ChangeHeightState::Start)));
+        tracker.observe(&key(ChangeHeight::Up(ChangeHeightState::Start)));
+        tracker.observe(&finished());
+        assert_eq!(tracker.height(Preset::One), None);
+    }
+}
@@ -0,0 +1,431 @@
+//! Differences between desk models that speak a variant of the protocol
+//!
+//! Everything else in the crate speaks the protocol of the captured desk. A [`DeskProfile`]
+//! describes how another desk differs from it (prefixes, key and register ids, the unit heights
+//! are sent in and the checksum) and translates packets at the edge: [`DeskProfile::decode`] turns
+//! a packet off the wire into the captured desk's [`BaseCommand`], and [`DeskProfile::encode`]
+//! does the reverse. The [`Framer`] also needs the profile to find where packets end.
+//!
+//! Responses are assumed to use the prefix after their event, as they do on the captured desk.
+//!
+//! [`Framer`]: crate::framer::Framer
+
+use crate::{
+    encoder::{encode_packet, write_escaped},
+    height::Height,
+    new_protocol::{
+        BaseCommand, ChangeHeight, EventResponse, IoError, Packet, PacketBuf, PacketBytes,
+        ProtocolError, ProtocolResult, Register, ReportHeight, Write, Writeable,
+    },
+};
+
+/// The offset of the payload in a packet with its start tag
+const PAYLOAD_START: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// The xor of everything from the prefix to the packet number. The captured desk
+    Xor,
+    /// The same bytes added together, wrapping
+    Sum,
+}
+impl Checksum {
+    /// Computes the checksum of a packet that still has its tags
+    pub fn compute(&self, raw_data: &[u8]) -> u8 {
+        // Skip the start tag, and the checksum and end tag at the end
+        let bytes = &raw_data[1..raw_data.len() - 2];
+        match self {
+            Checksum::Xor => bytes.iter().fold(0, |acc, &b| acc ^ b),
+            Checksum::Sum => bytes.iter().fold(0, |acc: u8, &b| acc.wrapping_add(b)),
+        }
+    }
+}
+
+/// The unit heights are sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeightUnit {
+    /// The captured desk. The same as [`Height`]
+    TenthsOfCentimetre,
+    Centimetre,
+    TenthsOfInch,
+}
+impl HeightUnit {
+    pub fn to_wire(&self, height: Height) -> u16 {
+        let mm = height.to_mm() as u32;
+        let raw = match self {
+            HeightUnit::TenthsOfCentimetre => mm,
+            HeightUnit::Centimetre => (mm + 5) / 10,
+            // 2.54mm to a tenth of an inch
+            HeightUnit::TenthsOfInch => (mm * 100 + 127) / 254,
+        };
+        raw.min(u16::MAX as u32) as u16
+    }
+
+    pub fn from_wire(&self, raw: u16) -> Height {
+        let raw = raw as u32;
+        let mm = match self {
+            HeightUnit::TenthsOfCentimetre => raw,
+            HeightUnit::Centimetre => raw * 10,
+            HeightUnit::TenthsOfInch => (raw * 254 + 50) / 100,
+        };
+        Height::from_mm(mm.min(u16::MAX as u32) as u16)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToWire,
+    ToCaptured,
+}
+
+/// How a desk model differs from the captured desk
+///
+/// The maps are pairs of the captured desk's id and this desk's id. Anything that isn't listed is
+/// the same on both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeskProfile {
+    pub name: &'static str,
+    /// Event prefixes
+    pub prefixes: &'static [(u8, u8)],
+    /// [`ChangeHeight`] command ids
+    pub keys: &'static [(u8, u8)],
+    pub registers: &'static [(Register, u8)],
+    pub height_unit: HeightUnit,
+    pub checksum: Checksum,
+}
+impl Default for DeskProfile {
+    fn default() -> Self {
+        Self::CAPTURED
+    }
+}
+impl DeskProfile {
+    /// The desk the captures in `data-captures` came from
+    pub const CAPTURED: Self = Self {
+        name: "captured",
+        prefixes: &[],
+        keys: &[],
+        registers: &[],
+        height_unit: HeightUnit::TenthsOfCentimetre,
+        checksum: Checksum::Xor,
+    };
+
+    pub const BUILT_IN: &'static [DeskProfile] = &[Self::CAPTURED];
+
+    pub fn find(name: &str) -> Option<&'static DeskProfile> {
+        Self::BUILT_IN.iter().find(|profile| profile.name == name)
+    }
+
+    /// The payload length of packets with `prefix` as it is sent on the wire
+    pub fn expected_payload_len(&self, prefix: u8) -> Option<usize> {
+        BaseCommand::expected_payload_len(self.prefix(prefix, Direction::ToCaptured))
+    }
+
+    pub fn verify_checksum(&self, packet: &Packet) -> ProtocolResult<()> {
+        let expected = self.checksum.compute(packet.as_bytes());
+        let actual = packet.get_checksum();
+        if expected != actual {
+            return Err(ProtocolError::BadCheckSum {
+                expected,
+                actual,
+                packet: PacketBytes::new(packet.as_bytes()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Decodes a packet from a desk of this model
+    pub fn decode(&self, packet: &Packet) -> ProtocolResult<BaseCommand> {
+        let captured = self.to_captured(packet)?;
+        BaseCommand::decode(&captured.as_packet())
+    }
+
+    /// Writes `command` to `writer` as a complete packet for a desk of this model
+    pub fn encode<C: Writeable, W: Write>(
+        &self,
+        command: &C,
+        packet_num: u16,
+        writer: &mut W,
+    ) -> Result<(), IoError<W::Error>> {
+        let packet = encode_packet(command, packet_num)?;
+        let wire = self.to_wire(&packet.as_packet())?;
+        write_escaped(wire.as_bytes(), writer).map_err(IoError::Io)
+    }
+
+    /// Rewrites a packet from a desk of this model as the captured desk would have sent it
+    pub fn to_captured(&self, packet: &Packet) -> ProtocolResult<PacketBuf> {
+        self.verify_checksum(packet)?;
+        let mut captured = self.translate(packet, Direction::ToCaptured)?;
+        captured.insert_checksum();
+        Ok(captured)
+    }
+
+    /// Rewrites a packet in the captured desk's protocol for a desk of this model
+    pub fn to_wire(&self, packet: &Packet) -> ProtocolResult<PacketBuf> {
+        packet.verify_checksum()?;
+        let mut wire = self.translate(packet, Direction::ToWire)?;
+        let bytes = wire.as_mut_bytes();
+        let len = bytes.len();
+        bytes[len - 2] = self.checksum.compute(bytes);
+        Ok(wire)
+    }
+
+    /// Maps the prefix, command id and any heights. The checksum is left for the caller
+    fn translate(&self, packet: &Packet, direction: Direction) -> ProtocolResult<PacketBuf> {
+        let mut buf = PacketBuf::try_from(*packet)?;
+        let prefix = self.prefix(packet.get_command_prefix(), direction);
+        // Only the captured desk's prefix says what the packet is
+        let captured_prefix = match direction {
+            Direction::ToWire => packet.get_command_prefix(),
+            Direction::ToCaptured => prefix,
+        };
+        let command_id = packet.get_command_id();
+        let (command_id, height_at) = match captured_prefix {
+            ChangeHeight::EVENT_ID | ChangeHeight::RESPONSE_ID => {
+                (map(self.keys.iter().copied(), command_id, direction), None)
+            }
+            Register::EVENT_ID | Register::RESPONSE_ID => {
+                let registers = self.registers.iter().map(|(r, id)| (*r as u8, *id));
+                let mapped = map(registers, command_id, direction);
+                let captured_id = match direction {
+                    Direction::ToWire => command_id,
+                    Direction::ToCaptured => mapped,
+                };
+                let is_height = matches!(
+                    Register::try_from(captured_id),
+                    Ok(Register::MinHeight
+                        | Register::MaxHeight
+                        | Register::UserMaxHeight
+                        | Register::UserMinHeight)
+                );
+                let height_at = (captured_prefix == Register::RESPONSE_ID && is_height)
+                    .then_some(PAYLOAD_START);
+                (mapped, height_at)
+            }
+            // The height follows a state byte
+            ReportHeight::EVENT_ID => (command_id, Some(PAYLOAD_START + 1)),
+            _ => (command_id, None),
+        };
+
+        let bytes = buf.as_mut_bytes();
+        bytes[1] = prefix;
+        bytes[2] = command_id;
+        // Heights are always 2 bytes, checked against the payload in case of a short packet
+        if let Some(at) = height_at.filter(|at| at + 2 <= PAYLOAD_START + packet.get_data().len()) {
+            let raw = u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+            let raw = match direction {
+                Direction::ToWire => self.height_unit.to_wire(Height::from_mm(raw)),
+                Direction::ToCaptured => self.height_unit.from_wire(raw).to_mm(),
+            };
+            bytes[at..at + 2].copy_from_slice(&raw.to_be_bytes());
+        }
+        Ok(buf)
+    }
+
+    fn prefix(&self, prefix: u8, direction: Direction) -> u8 {
+        let mapped = map(self.prefixes.iter().copied(), prefix, direction);
+        if mapped != prefix {
+            return mapped;
+        }
+        // A response uses the prefix after its event's
+        let event = prefix.wrapping_sub(1);
+        let mapped = map(self.prefixes.iter().copied(), event, direction);
+        if mapped != event {
+            mapped.wrapping_add(1)
+        } else {
+            prefix
+        }
+    }
+}
+
+/// Looks `value` up in pairs of (captured, wire) ids
+fn map(pairs: impl IntoIterator<Item = (u8, u8)>, value: u8, direction: Direction) -> u8 {
+    pairs
+        .into_iter()
+        .find_map(|(captured, wire)| match direction {
+            Direction::ToWire if captured == value => Some(wire),
+            Direction::ToCaptured if wire == value => Some(captured),
+            _ => None,
+        })
+        .unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        framer::Framer,
+        new_protocol::{
+            tests::{packet, Wire},
+            ChangeHeightState, Command, RegisterValue,
+        },
+    };
+
+    /// A desk that differs in every way a profile allows
+    const TEST: DeskProfile = DeskProfile {
+        name: "test",
+        prefixes: &[(0x17, 0x27), (0x03, 0x05), (0x15, 0x35)],
+        keys: &[(0x03, 0x0A)],
+        registers: &[(Register::MinHeight, 0x31)],
+        height_unit: HeightUnit::TenthsOfInch,
+        checksum: Checksum::Sum,
+    };
+
+    fn to_wire(profile: &DeskProfile, body: &[u8]) -> PacketBuf {
+        profile.to_wire(&packet(body, 1).as_packet()).unwrap()
+    }
+
+    /// Translates `body` for [`TEST`] and back again
+    fn round_trip(body: &[u8], wire: &[u8]) {
+        let captured = packet(body, 1);
+        let translated = TEST.to_wire(&captured.as_packet()).unwrap();
+        assert_eq!(translated.as_bytes(), wire);
+        let back = TEST.to_captured(&translated.as_packet()).unwrap();
+        assert_eq!(back.as_bytes(), captured.as_bytes());
+    }
+
+    #[test]
+    fn captured_is_unchanged() {
+        let body = [0x16, 0x21, 0x02, 0x8A];
+        let captured = packet(&body, 1);
+        assert_eq!(
+            to_wire(&DeskProfile::CAPTURED, &body).as_bytes(),
+            captured.as_bytes()
+        );
+        assert_eq!(DeskProfile::find("captured"), Some(&DeskProfile::CAPTURED));
+        assert_eq!(DeskProfile::find("test"), None);
+    }
+
+    #[test]
+    fn remaps_key() {
+        // 27 + 0A + 01 + 00 + 01
+        round_trip(
+            &[0x17, 0x03, 0x01],
+            &[0xFA, 0x27, 0x0A, 0x01, 0x00, 0x01, 0x33, 0xFD],
+        );
+        // Keys that aren't listed keep their id
+        round_trip(
+            &[0x17, 0x04, 0x01],
+            &[0xFA, 0x27, 0x04, 0x01, 0x00, 0x01, 0x2D, 0xFD],
+        );
+    }
+
+    #[test]
+    fn guesses_response_prefix() {
+        // The key ack is the prefix after the remapped key press
+        round_trip(
+            &[0x18, 0x03, 0x01, 0x00],
+            &[0xFA, 0x28, 0x0A, 0x01, 0x00, 0x00, 0x01, 0x34, 0xFD],
+        );
+        assert_eq!(TEST.prefix(0x18, Direction::ToWire), 0x28);
+        assert_eq!(TEST.prefix(0x28, Direction::ToCaptured), 0x18);
+        assert_eq!(TEST.prefix(0x36, Direction::ToCaptured), 0x16);
+        // Neither the prefix nor the one before it are listed
+        assert_eq!(TEST.prefix(0xA1, Direction::ToWire), 0xA1);
+        assert_eq!(TEST.prefix(0x00, Direction::ToWire), 0x00);
+    }
+
+    #[test]
+    fn listed_response_prefix_wins() {
+        const PROFILE: DeskProfile = DeskProfile {
+            name: "response",
+            prefixes: &[(0x17, 0x27), (0x18, 0x40)],
+            ..DeskProfile::CAPTURED
+        };
+        assert_eq!(PROFILE.prefix(0x18, Direction::ToWire), 0x40);
+        assert_eq!(PROFILE.prefix(0x40, Direction::ToCaptured), 0x18);
+    }
+
+    #[test]
+    fn remaps_register_and_height() {
+        // 650mm is 25.6 inches
+        round_trip(
+            &[0x16, 0x21, 0x02, 0x8A],
+            &[0xFA, 0x36, 0x31, 0x01, 0x00, 0x00, 0x01, 0x69, 0xFD],
+        );
+        // Only the remapped register changes id, and units aren't a height
+        round_trip(
+            &[0x16, 0x23, 0x00, 0x00],
+            &[0xFA, 0x36, 0x23, 0x00, 0x00, 0x00, 0x01, 0x5A, 0xFD],
+        );
+        round_trip(&[0x15, 0x21], &[0xFA, 0x35, 0x31, 0x00, 0x01, 0x67, 0xFD]);
+    }
+
+    #[test]
+    fn converts_reported_height() {
+        // 724mm is 28.5 inches
+        round_trip(
+            &[0x03, 0x00, 0x01, 0x02, 0xD4],
+            &[0xFA, 0x05, 0x00, 0x01, 0x01, 0x1D, 0x00, 0x01, 0x25, 0xFD],
+        );
+    }
+
+    #[test]
+    fn height_units() {
+        let height = Height::from_mm(724);
+        assert_eq!(HeightUnit::TenthsOfCentimetre.to_wire(height), 724);
+        assert_eq!(HeightUnit::Centimetre.to_wire(height), 72);
+        assert_eq!(HeightUnit::Centimetre.from_wire(72), Height::from_mm(720));
+        assert_eq!(HeightUnit::TenthsOfInch.to_wire(height), 285);
+        assert_eq!(HeightUnit::TenthsOfInch.from_wire(285), height);
+    }
+
+    #[test]
+    fn checksums() {
+        let wire = to_wire(&TEST, &[0x17, 0x03, 0x01]);
+        assert_eq!(Checksum::Sum.compute(wire.as_bytes()), 0x33);
+        assert_eq!(Checksum::Xor.compute(wire.as_bytes()), 0x2D);
+        // A packet with the captured desk's checksum is rejected
+        let captured = packet(&[0x27, 0x0A, 0x01], 1);
+        assert!(matches!(
+            TEST.to_captured(&captured.as_packet()),
+            Err(ProtocolError::BadCheckSum {
+                expected: 0x33,
+                actual: 0x2D,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn payload_lengths() {
+        assert_eq!(TEST.expected_payload_len(0x27), Some(1));
+        assert_eq!(TEST.expected_payload_len(0x28), Some(2));
+        assert_eq!(TEST.expected_payload_len(0x05), Some(3));
+        assert_eq!(TEST.expected_payload_len(0x36), Some(2));
+        assert_eq!(TEST.expected_payload_len(0x13), Some(3));
+        assert_eq!(TEST.expected_payload_len(0x42), None);
+    }
+
+    #[test]
+    fn encodes_and_decodes() {
+        let register = BaseCommand::HandShake(Command::Reponse(RegisterValue::MinHeight(
+            Height::from_mm(650),
+        )));
+        let mut wire = Wire(std::vec::Vec::new());
+        TEST.encode(&register, 1, &mut wire).unwrap();
+
+        let mut framer = Framer::<{ crate::new_protocol::MAX_PACKET_LEN }>::with_profile(&TEST);
+        let (last, bytes) = wire.0.split_last().unwrap();
+        for &byte in bytes {
+            assert!(framer.push(byte).unwrap().is_none());
+        }
+        let packet = framer.push(*last).unwrap().unwrap();
+        assert!(matches!(
+            TEST.decode(&packet).unwrap(),
+            BaseCommand::HandShake(Command::Reponse(RegisterValue::MinHeight(height)))
+                if height == Height::from_mm(650)
+        ));
+
+        let key = packet_of(&TEST, ChangeHeight::Up(ChangeHeightState::Start));
+        assert!(matches!(
+            TEST.decode(&key.as_packet()).unwrap(),
+            BaseCommand::ChangeHeight(Command::Command(ChangeHeight::Up(ChangeHeightState::Start)))
+        ));
+    }
+
+    fn packet_of(profile: &DeskProfile, key: ChangeHeight) -> PacketBuf {
+        let command = BaseCommand::ChangeHeight(Command::Command(key));
+        let packet = encode_packet(&command, 1).unwrap();
+        profile.to_wire(&packet.as_packet()).unwrap()
+    }
+}
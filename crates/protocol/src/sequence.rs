@@ -0,0 +1,321 @@
+//! Tracks the 16 bit packet number that the desk and the controller each count up with
+//!
+//! Both sides number their own packets independently. The desk keeps counting from wherever it
+//! was (0x17xx in the connect capture) while the controller starts again from 0x0001 when it
+//! connects, so a jump backwards is treated as the other side having restarted.
+
+use crate::new_protocol::Source;
+
+/// How far a packet number can jump forward before it is treated as a reset instead of a gap
+pub const DEFAULT_MAX_GAP: u16 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceEvent {
+    /// The first packet seen in this direction
+    First,
+    InOrder,
+    /// The counter went from 0xFFFF back to 0x0000
+    Wrapped,
+    /// Some packets were lost. `missed` is how many
+    Gap {
+        expected: u16,
+        actual: u16,
+        missed: u16,
+    },
+    Duplicate(u16),
+    /// The counter jumped backwards or too far forwards, usually because the device rebooted
+    Reset {
+        previous: u16,
+        actual: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+    pub received: u32,
+    pub missed: u32,
+    pub gaps: u32,
+    pub duplicates: u32,
+    pub wraps: u32,
+    pub resets: u32,
+}
+
+/// Follows the packet numbers for one direction of the link
+#[derive(Debug, Clone)]
+pub struct SequenceTracker {
+    last: Option<u16>,
+    max_gap: u16,
+    stats: SequenceStats,
+}
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_GAP)
+    }
+}
+impl SequenceTracker {
+    pub const fn new(max_gap: u16) -> Self {
+        Self {
+            last: None,
+            max_gap,
+            stats: SequenceStats {
+                received: 0,
+                missed: 0,
+                gaps: 0,
+                duplicates: 0,
+                wraps: 0,
+                resets: 0,
+            },
+        }
+    }
+
+    pub fn last(&self) -> Option<u16> {
+        self.last
+    }
+
+    pub fn stats(&self) -> &SequenceStats {
+        &self.stats
+    }
+
+    /// Records the next packet number and reports how it relates to the previous one
+    pub fn observe(&mut self, packet_num: u16) -> SequenceEvent {
+        self.stats.received += 1;
+        let Some(previous) = self.last.replace(packet_num) else {
+            return SequenceEvent::First;
+        };
+        let expected = previous.wrapping_add(1);
+        match packet_num.wrapping_sub(previous) {
+            0 => {
+                self.stats.duplicates += 1;
+                SequenceEvent::Duplicate(packet_num)
+            }
+            1 if packet_num == 0 => {
+                self.stats.wraps += 1;
+                SequenceEvent::Wrapped
+            }
+            1 => SequenceEvent::InOrder,
+            distance if distance <= self.max_gap => {
+                let missed = distance - 1;
+                self.stats.gaps += 1;
+                self.stats.missed += missed as u32;
+                if packet_num < previous {
+                    self.stats.wraps += 1;
+                }
+                SequenceEvent::Gap {
+                    expected,
+                    actual: packet_num,
+                    missed,
+                }
+            }
+            _ => {
+                self.stats.resets += 1;
+                SequenceEvent::Reset {
+                    previous,
+                    actual: packet_num,
+                }
+            }
+        }
+    }
+}
+
+/// A [`SequenceTracker`] for each side of the link
+#[derive(Debug, Clone, Default)]
+pub struct LinkSequences {
+    pub desk: SequenceTracker,
+    pub controller: SequenceTracker,
+}
+impl LinkSequences {
+    pub fn tracker(&mut self, source: Source) -> &mut SequenceTracker {
+        match source {
+            Source::Desk => &mut self.desk,
+            Source::Controller => &mut self.controller,
+        }
+    }
+
+    pub fn observe(&mut self, source: Source, packet_num: u16) -> SequenceEvent {
+        self.tracker(source).observe(packet_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_and_in_order() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.last(), None);
+        assert_eq!(tracker.observe(0x1705), SequenceEvent::First);
+        assert_eq!(tracker.observe(0x1706), SequenceEvent::InOrder);
+        assert_eq!(tracker.observe(0x1707), SequenceEvent::InOrder);
+        assert_eq!(tracker.last(), Some(0x1707));
+        assert_eq!(
+            *tracker.stats(),
+            SequenceStats {
+                received: 3,
+                ..SequenceStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn gap() {
+        let mut tracker = SequenceTracker::default();
+        tracker.observe(10);
+        assert_eq!(
+            tracker.observe(13),
+            SequenceEvent::Gap {
+                expected: 11,
+                actual: 13,
+                missed: 2,
+            }
+        );
+        assert_eq!(tracker.observe(14), SequenceEvent::InOrder);
+        // The furthest jump that still counts as a gap
+        assert_eq!(
+            tracker.observe(14 + DEFAULT_MAX_GAP),
+            SequenceEvent::Gap {
+                expected: 15,
+                actual: 14 + DEFAULT_MAX_GAP,
+                missed: DEFAULT_MAX_GAP - 1,
+            }
+        );
+        assert_eq!(
+            *tracker.stats(),
+            SequenceStats {
+                received: 4,
+                missed: 2 + DEFAULT_MAX_GAP as u32 - 1,
+                gaps: 2,
+                ..SequenceStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut tracker = SequenceTracker::default();
+        tracker.observe(7);
+        assert_eq!(tracker.observe(7), SequenceEvent::Duplicate(7));
+        assert_eq!(tracker.observe(8), SequenceEvent::InOrder);
+        assert_eq!(
+            *tracker.stats(),
+            SequenceStats {
+                received: 3,
+                duplicates: 1,
+                ..SequenceStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn wrapped() {
+        let mut tracker = SequenceTracker::default();
+        tracker.observe(0xFFFF);
+        assert_eq!(tracker.observe(0x0000), SequenceEvent::Wrapped);
+        assert_eq!(tracker.observe(0x0001), SequenceEvent::InOrder);
+        assert_eq!(
+            *tracker.stats(),
+            SequenceStats {
+                received: 3,
+                wraps: 1,
+                ..SequenceStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn gap_across_the_wrap() {
+        let mut tracker = SequenceTracker::default();
+        tracker.observe(0xFFFE);
+        // 0xFFFF and 0x0000 were lost
+        assert_eq!(
+            tracker.observe(0x0001),
+            SequenceEvent::Gap {
+                expected: 0xFFFF,
+                actual: 0x0001,
+                missed: 2,
+            }
+        );
+        assert_eq!(
+            *tracker.stats(),
+            SequenceStats {
+                received: 2,
+                missed: 2,
+                gaps: 1,
+                wraps: 1,
+                ..SequenceStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reset() {
+        let mut tracker = SequenceTracker::default();
+        tracker.observe(0x1705);
+        // The desk rebooted and started counting again
+        assert_eq!(
+            tracker.observe(0x0001),
+            SequenceEvent::Reset {
+                previous: 0x1705,
+                actual: 0x0001,
+            }
+        );
+        assert_eq!(tracker.observe(0x0002), SequenceEvent::InOrder);
+        // One past the largest gap is a reset as well
+        assert_eq!(
+            tracker.observe(0x0003 + DEFAULT_MAX_GAP),
+            SequenceEvent::Reset {
+                previous: 0x0002,
+                actual: 0x0003 + DEFAULT_MAX_GAP,
+            }
+        );
+        assert_eq!(
+            *tracker.stats(),
+            SequenceStats {
+                received: 4,
+                resets: 2,
+                ..SequenceStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn link_sequences_are_independent() {
+        let mut link = LinkSequences::default();
+        assert_eq!(link.observe(Source::Desk, 0x1705), SequenceEvent::First);
+        assert_eq!(
+            link.observe(Source::Controller, 0x0001),
+            SequenceEvent::First
+        );
+        assert_eq!(link.observe(Source::Desk, 0x1706), SequenceEvent::InOrder);
+        assert_eq!(
+            link.observe(Source::Controller, 0x0003),
+            SequenceEvent::Gap {
+                expected: 0x0002,
+                actual: 0x0003,
+                missed: 1,
+            }
+        );
+        assert_eq!(
+            link.observe(Source::Desk, 0x1706),
+            SequenceEvent::Duplicate(0x1706)
+        );
+        assert_eq!(link.tracker(Source::Desk).last(), Some(0x1706));
+        assert_eq!(
+            *link.desk.stats(),
+            SequenceStats {
+                received: 3,
+                duplicates: 1,
+                ..SequenceStats::default()
+            }
+        );
+        assert_eq!(
+            *link.controller.stats(),
+            SequenceStats {
+                received: 2,
+                missed: 1,
+                gaps: 1,
+                ..SequenceStats::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! Bringing a desk up from the keypad's side of the link
+//!
+//! The `connect` capture shows the keypad doing the same thing every time the desk powers on:
+//!
+//! 1. Connect with 0x11 until the desk answers 0x12 with true
+//! 2. Identify itself with 0x13, then ask for every [`Register`] with 0x15 without waiting for
+//!    any of the answers
+//! 3. The desk acks the identity with 0x14, sends its own identity with 0x13 (which the keypad
+//!    acks) and answers each register with 0x16
+//! 4. The keypad sends a [`ControllerState`] heartbeat every 200ms from then on
+//!
+//! [`ControllerSession`] does the keypad's half of this. Feed it every packet from the desk with
+//! [`ControllerSession::observe`] and call [`ControllerSession::poll`] regularly, sending
+//! whatever it returns until it returns `None`. Anything that isn't answered in time is sent
+//! again, and the session starts over from connecting if the desk keeps ignoring it or goes
+//! quiet. Times are milliseconds from any monotonic clock.
+
+use crate::{
+    config::{DeskConfig, DeskConfigBuilder},
+    new_protocol::{
+        BaseCommand, Command, Connect, ControllerState, DeviceIdentity, IdentityAck, Register,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// How long to wait between connect attempts. The desk may still be booting so this is
+    /// retried forever
+    pub connect_retry_ms: u64,
+    /// How long to wait for the desk to answer the identity and register queries
+    pub reply_timeout_ms: u64,
+    /// How many times to resend unanswered queries before starting over
+    pub max_retries: u8,
+    pub heartbeat_ms: u64,
+    /// How long the desk can go without sending anything before the link is treated as lost.
+    /// The desk reports its height every 100ms while connected
+    pub desk_timeout_ms: u64,
+}
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+impl SessionConfig {
+    pub const DEFAULT: Self = Self {
+        // The captured keypad tried again after 3s
+        connect_retry_ms: 3000,
+        // The captured desk answered everything within 15ms
+        reply_timeout_ms: 250,
+        max_retries: 3,
+        heartbeat_ms: 200,
+        desk_timeout_ms: 1000,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// Waiting for the desk to answer the connect
+    Connecting,
+    /// Waiting for the desk to ack the keypad's identity and send its own
+    Identifying,
+    /// Waiting for the desk to answer the register queries
+    Configuring,
+    /// Sending heartbeats. The desk's identity and configuration are known
+    Ready,
+}
+
+/// The keypad's side of bringing up the link
+#[derive(Debug, Clone)]
+pub struct ControllerSession {
+    identity: DeviceIdentity,
+    config: SessionConfig,
+    connected: bool,
+    identity_acked: bool,
+    desk_identity: Option<DeviceIdentity>,
+    registers: DeskConfigBuilder,
+    desk_config: Option<DeskConfig>,
+    /// The desk's identity still has to be acked
+    pending_ack: Option<IdentityAck>,
+    /// When the connect or the current round of queries was sent
+    sent_ms: Option<u64>,
+    /// The index into [`Register::ALL`] of the next register to ask for in this round
+    next_query: Option<usize>,
+    retries: u8,
+    last_heartbeat_ms: Option<u64>,
+    last_desk_ms: Option<u64>,
+}
+impl Default for ControllerSession {
+    fn default() -> Self {
+        Self::new(DeviceIdentity::KEYPAD, SessionConfig::DEFAULT)
+    }
+}
+impl ControllerSession {
+    /// `identity` is what is sent to the desk. [`DeviceIdentity::KEYPAD`] is what the captured
+    /// keypad sends
+    pub fn new(identity: DeviceIdentity, config: SessionConfig) -> Self {
+        Self {
+            identity,
+            config,
+            connected: false,
+            identity_acked: false,
+            desk_identity: None,
+            registers: DeskConfigBuilder::new(),
+            desk_config: None,
+            pending_ack: None,
+            sent_ms: None,
+            next_query: None,
+            retries: 0,
+            last_heartbeat_ms: None,
+            last_desk_ms: None,
+        }
+    }
+
+    /// Forgets everything about the desk and starts over from connecting
+    pub fn reset(&mut self) {
+        *self = Self::new(self.identity, self.config);
+    }
+
+    pub fn state(&self) -> SessionState {
+        if !self.connected {
+            SessionState::Connecting
+        } else if !self.identity_acked || self.desk_identity.is_none() {
+            SessionState::Identifying
+        } else if self.desk_config.is_none() {
+            SessionState::Configuring
+        } else {
+            SessionState::Ready
+        }
+    }
+
+    pub fn desk_identity(&self) -> Option<DeviceIdentity> {
+        self.desk_identity
+    }
+
+    /// The desk's configuration once every register has been answered
+    pub fn desk_config(&self) -> Option<DeskConfig> {
+        self.desk_config
+    }
+
+    /// Updates the session from a packet the desk sent. Everything else is ignored
+    pub fn observe(&mut self, command: &BaseCommand, now_ms: u64) {
+        self.last_desk_ms = Some(now_ms);
+        match command {
+            BaseCommand::Connect(Command::Reponse(connect))
+                if connect.connected && !self.connected =>
+            {
+                self.connected = true;
+                self.sent_ms = None;
+                self.retries = 0;
+            }
+            BaseCommand::Identify(Command::Reponse(ack)) if ack.role == self.identity.role => {
+                self.identity_acked = true;
+                self.retries = 0;
+            }
+            BaseCommand::Identify(Command::Command(identity))
+                if identity.role != self.identity.role =>
+            {
+                self.desk_identity = Some(*identity);
+                self.pending_ack = Some(identity.ack());
+            }
+            BaseCommand::HandShake(Command::Reponse(value)) => {
+                self.registers.set(*value);
+                self.desk_config = self.registers.build().ok();
+                self.retries = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// The next packet to send, if any. Call again until this returns `None`
+    pub fn poll(&mut self, now_ms: u64) -> Option<BaseCommand> {
+        if let Some(ack) = self.pending_ack.take() {
+            return Some(BaseCommand::Identify(Command::Reponse(ack)));
+        }
+        let desk_quiet = self
+            .last_desk_ms
+            .is_some_and(|last| now_ms.saturating_sub(last) > self.config.desk_timeout_ms);
+        if self.connected && desk_quiet {
+            self.reset();
+        }
+        match self.state() {
+            SessionState::Connecting => {
+                let waiting = self
+                    .elapsed(now_ms)
+                    .is_some_and(|elapsed| elapsed < self.config.connect_retry_ms);
+                if waiting {
+                    return None;
+                }
+                self.sent_ms = Some(now_ms);
+                Some(BaseCommand::Connect(Command::Command(Connect {})))
+            }
+            SessionState::Identifying | SessionState::Configuring => self.query(now_ms),
+            SessionState::Ready => {
+                let due = self
+                    .last_heartbeat_ms
+                    .is_none_or(|last| now_ms.saturating_sub(last) >= self.config.heartbeat_ms);
+                if !due {
+                    return None;
+                }
+                self.last_heartbeat_ms = Some(now_ms);
+                Some(BaseCommand::ReportControllerState(Command::Command(
+                    ControllerState::OK,
+                )))
+            }
+        }
+    }
+
+    /// Time since the connect or the current round of queries was sent
+    fn elapsed(&self, now_ms: u64) -> Option<u64> {
+        self.sent_ms.map(|sent| now_ms.saturating_sub(sent))
+    }
+
+    /// Sends the identity and every unanswered register query, then waits for the answers and
+    /// tries again if they don't all come
+    fn query(&mut self, now_ms: u64) -> Option<BaseCommand> {
+        if let Some(index) = self.next_query {
+            let missing = Register::ALL[index..]
+                .iter()
+                .position(|register| self.registers.get(*register).is_none());
+            match missing {
+                Some(offset) => {
+                    self.next_query = Some(index + offset + 1);
+                    let register = Register::ALL[index + offset];
+                    return Some(BaseCommand::HandShake(Command::Command(register)));
+                }
+                None => self.next_query = None,
+            }
+        }
+        match self.elapsed(now_ms) {
+            Some(elapsed) if elapsed < self.config.reply_timeout_ms => return None,
+            Some(_) if self.retries >= self.config.max_retries => {
+                self.reset();
+                return self.poll(now_ms);
+            }
+            Some(_) => self.retries += 1,
+            None => {}
+        }
+        self.sent_ms = Some(now_ms);
+        self.next_query = Some(0);
+        if self.identity_acked && self.desk_identity.is_some() {
+            return self.query(now_ms);
+        }
+        // The desk only sends its identity after acking ours, so ours is sent again until both
+        // have been seen
+        Some(BaseCommand::Identify(Command::Command(self.identity)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::new_protocol::tests::decode;
+
+    /// What the desk sent in the `connect` capture, in order
+    const CAPTURED_DESK: &[&[u8]] = &[
+        &[0xFA, 0x12, 0x01, 0x01, 0x17, 0x93, 0x96, 0xFD],
+        &[0xFA, 0x14, 0x03, 0xFF, 0x00, 0x17, 0x94, 0x6B, 0xFD],
+        &[0xFA, 0x13, 0x01, 0xFF, 0x03, 0xE8, 0x17, 0x95, 0x84, 0xFD],
+        &[0xFA, 0x16, 0x13, 0x00, 0x01, 0x17, 0x96, 0x85, 0xFD],
+        &[0xFA, 0x16, 0x14, 0x00, 0x01, 0x17, 0x97, 0x83, 0xFD],
+        &[0xFA, 0x16, 0x15, 0x00, 0x01, 0x17, 0x98, 0x8D, 0xFD],
+        &[0xFA, 0x16, 0x21, 0x02, 0x8A, 0x17, 0x99, 0x31, 0xFD],
+        &[0xFA, 0x16, 0x22, 0x04, 0xE2, 0x17, 0x9A, 0x5F, 0xFD],
+        &[0xFA, 0x16, 0x23, 0x00, 0x00, 0x17, 0x9B, 0xB9, 0xFD],
+        &[0xFA, 0x16, 0x72, 0x04, 0xE2, 0x17, 0x9C, 0x09, 0xFD],
+        &[0xFA, 0x16, 0x73, 0x02, 0x8A, 0x17, 0x9D, 0x67, 0xFD],
+    ];
+
+    /// Index of the 0x22 answer in [`CAPTURED_DESK`]
+    const MAX_HEIGHT: usize = 7;
+
+    fn drain(session: &mut ControllerSession, now_ms: u64) -> Vec<BaseCommand> {
+        core::iter::from_fn(|| session.poll(now_ms)).collect()
+    }
+
+    /// Plays the captured desk's side of the handshake, leaving out `skip`
+    fn handshake(session: &mut ControllerSession, skip: Option<usize>) {
+        assert!(matches!(
+            drain(session, 0)[..],
+            [BaseCommand::Connect(Command::Command(_))]
+        ));
+        session.observe(&decode(CAPTURED_DESK[0]).0, 10);
+        assert_eq!(session.state(), SessionState::Identifying);
+
+        let sent = drain(session, 10);
+        assert!(matches!(
+            sent[0],
+            BaseCommand::Identify(Command::Command(identity)) if identity == DeviceIdentity::KEYPAD
+        ));
+        let queries: Vec<Register> = sent[1..]
+            .iter()
+            .map(|command| match command {
+                BaseCommand::HandShake(Command::Command(register)) => *register,
+                _ => panic!("{command:?}"),
+            })
+            .collect();
+        assert_eq!(queries, Register::ALL);
+
+        for (index, captured) in CAPTURED_DESK.iter().enumerate().skip(1) {
+            if Some(index) != skip {
+                session.observe(&decode(captured).0, 20);
+            }
+        }
+    }
+
+    #[test]
+    fn retries_connect() {
+        let mut session = ControllerSession::default();
+        assert!(session.poll(0).is_some());
+        assert!(session.poll(2999).is_none());
+        assert!(matches!(
+            session.poll(3000),
+            Some(BaseCommand::Connect(Command::Command(_)))
+        ));
+        assert_eq!(session.state(), SessionState::Connecting);
+    }
+
+    #[test]
+    fn ready_with_captured_config() {
+        let mut session = ControllerSession::default();
+        handshake(&mut session, None);
+        assert!(matches!(
+            drain(&mut session, 20)[..],
+            [
+                BaseCommand::Identify(Command::Reponse(ack)),
+                BaseCommand::ReportControllerState(Command::Command(ControllerState::OK)),
+            ] if ack == DeviceIdentity::DESK.ack()
+        ));
+        assert_eq!(session.state(), SessionState::Ready);
+        assert_eq!(session.desk_identity(), Some(DeviceIdentity::DESK));
+        assert_eq!(session.desk_config(), Some(DeskConfig::DEFAULT));
+
+        assert!(drain(&mut session, 219).is_empty());
+        assert_eq!(drain(&mut session, 220).len(), 1);
+    }
+
+    #[test]
+    fn resends_lost_register() {
+        let mut session = ControllerSession::default();
+        handshake(&mut session, Some(MAX_HEIGHT));
+        // Only the identity ack, the answers aren't late yet
+        assert_eq!(drain(&mut session, 20).len(), 1);
+        assert_eq!(session.state(), SessionState::Configuring);
+        assert!(drain(&mut session, 259).is_empty());
+
+        assert!(matches!(
+            drain(&mut session, 260)[..],
+            [BaseCommand::HandShake(Command::Command(
+                Register::MaxHeight
+            ))]
+        ));
+        session.observe(&decode(CAPTURED_DESK[MAX_HEIGHT]).0, 270);
+        assert_eq!(session.state(), SessionState::Ready);
+        assert_eq!(session.desk_config(), Some(DeskConfig::DEFAULT));
+    }
+
+    #[test]
+    fn starts_over_when_ignored() {
+        let mut session = ControllerSession::default();
+        handshake(&mut session, Some(MAX_HEIGHT));
+        drain(&mut session, 20);
+        for retry in 1..=SessionConfig::DEFAULT.max_retries as u64 {
+            assert_eq!(drain(&mut session, 20 + retry * 250).len(), 1);
+        }
+        assert!(matches!(
+            drain(&mut session, 20 + 4 * 250)[..],
+            [BaseCommand::Connect(Command::Command(_))]
+        ));
+        assert_eq!(session.state(), SessionState::Connecting);
+        assert_eq!(session.desk_identity(), None);
+    }
+
+    #[test]
+    fn resets_after_desk_timeout() {
+        let mut session = ControllerSession::default();
+        handshake(&mut session, None);
+        drain(&mut session, 20);
+        assert!(drain(&mut session, 1000)
+            .iter()
+            .all(|command| matches!(command, BaseCommand::ReportControllerState(_))));
+        assert_eq!(session.state(), SessionState::Ready);
+
+        assert!(matches!(
+            drain(&mut session, 1021)[..],
+            [BaseCommand::Connect(Command::Command(_))]
+        ));
+        assert_eq!(session.state(), SessionState::Connecting);
+        assert_eq!(session.desk_config(), None);
+    }
+}
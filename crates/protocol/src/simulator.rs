@@ -0,0 +1,429 @@
+//! A stand in for the desk so a keypad can be developed without one
+//!
+//! [`DeskSimulator`] answers the way the captured desk does: 0x12 to a connect, 0x14 and its own
+//! 0x13 to an identity, 0x16 to each register query, and a 0x18 for every key press and every
+//! release except the preset keys. Once connected it reports its height every 100ms. Holding Up
+//! or Down moves it, and a preset key moves it to the preset set with
+//! [`DeskSimulator::set_preset`]. It sends `A0 00` when it stops. Saving from the keypad with
+//! [`Preset::experimental_save`] isn't simulated since no save has been captured.
+//!
+//! Unlike the real desk it starts moving straight away and stops dead when the key is released.
+//!
+//! Feed it every packet from the keypad with [`DeskSimulator::observe`] and call
+//! [`DeskSimulator::poll`] regularly, sending whatever it returns until it returns `None`. Times
+//! are milliseconds from any monotonic clock.
+
+use crate::{
+    config::DeskConfig,
+    height::Height,
+    new_protocol::{
+        BaseCommand, ChangeHeight, ChangeHeightResponse, ChangeHeightState, Command,
+        ConnectResponse, DeviceIdentity, MoveFinished, Register, ReportHeight, ResponseState,
+    },
+    preset::Preset,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimulatorConfig {
+    pub identity: DeviceIdentity,
+    /// The register values. The desk won't move outside of its effective limits
+    pub desk: DeskConfig,
+    /// Where the desk starts
+    pub height: Height,
+    /// How fast the desk moves in tenths of a centimetre a second
+    pub speed: u16,
+    pub report_ms: u64,
+}
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+impl SimulatorConfig {
+    pub const DEFAULT: Self = Self {
+        identity: DeviceIdentity::DESK,
+        desk: DeskConfig::DEFAULT,
+        // Where the desk in the `connect` capture was
+        height: Height::from_mm(724),
+        // The captured desk moves about 2.5cm/s
+        speed: 25,
+        report_ms: 100,
+    };
+}
+
+/// How many key acks can be waiting to be sent. A press and a release between polls
+const MAX_KEY_ACKS: usize = 2;
+
+#[derive(Debug, Clone)]
+pub struct DeskSimulator {
+    config: SimulatorConfig,
+    presets: [Option<Height>; Preset::ALL.len()],
+    connected: bool,
+    /// Micrometres so slow speeds and frequent polls still move the desk
+    position_um: u32,
+    /// Where the desk is moving to, the limit while Up or Down is held
+    target: Option<Height>,
+    /// Up or Down is held rather than a preset being recalled
+    held: bool,
+    last_tick_ms: Option<u64>,
+    last_report_ms: Option<u64>,
+    // Replies waiting to be sent
+    connect_reply: bool,
+    identity_reply: Option<DeviceIdentity>,
+    send_identity: bool,
+    /// Bit n is set when [`Register::ALL`]\[n\] has been asked for
+    register_replies: u8,
+    key_acks: [Option<ChangeHeight<ChangeHeightResponse>>; MAX_KEY_ACKS],
+    move_finished: bool,
+}
+impl Default for DeskSimulator {
+    fn default() -> Self {
+        Self::new(SimulatorConfig::DEFAULT)
+    }
+}
+impl DeskSimulator {
+    pub fn new(config: SimulatorConfig) -> Self {
+        Self {
+            config,
+            presets: [None; Preset::ALL.len()],
+            connected: false,
+            position_um: config.height.to_mm() as u32 * 1000,
+            target: None,
+            held: false,
+            last_tick_ms: None,
+            last_report_ms: None,
+            connect_reply: false,
+            identity_reply: None,
+            send_identity: false,
+            register_replies: 0,
+            key_acks: [None; MAX_KEY_ACKS],
+            move_finished: false,
+        }
+    }
+
+    pub fn height(&self) -> Height {
+        Height::from_mm((self.position_um / 1000) as u16)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.target.is_some()
+    }
+
+    pub fn preset(&self, preset: Preset) -> Option<Height> {
+        self.presets[preset as usize]
+    }
+
+    pub fn set_preset(&mut self, preset: Preset, height: Height) {
+        self.presets[preset as usize] = Some(height);
+    }
+
+    /// Reacts to a packet from the keypad. Everything else is ignored
+    pub fn observe(&mut self, command: &BaseCommand, now_ms: u64) {
+        self.tick(now_ms);
+        match command {
+            BaseCommand::Connect(Command::Command(_)) => {
+                self.connected = true;
+                self.connect_reply = true;
+            }
+            BaseCommand::Identify(Command::Command(identity)) => {
+                self.identity_reply = Some(*identity);
+                self.send_identity = true;
+            }
+            BaseCommand::HandShake(Command::Command(register)) => {
+                if let Some(index) = Register::ALL.iter().position(|r| r == register) {
+                    self.register_replies |= 1 << index;
+                }
+            }
+            BaseCommand::ChangeHeight(Command::Command(key)) => self.key(key),
+            _ => {}
+        }
+    }
+
+    /// The next packet to send, if any. Call again until this returns `None`
+    pub fn poll(&mut self, now_ms: u64) -> Option<BaseCommand> {
+        self.tick(now_ms);
+        if core::mem::take(&mut self.connect_reply) {
+            return Some(BaseCommand::Connect(Command::Reponse(ConnectResponse {
+                connected: true,
+            })));
+        }
+        if let Some(identity) = self.identity_reply.take() {
+            return Some(BaseCommand::Identify(Command::Reponse(identity.ack())));
+        }
+        if core::mem::take(&mut self.send_identity) {
+            return Some(BaseCommand::Identify(Command::Command(
+                self.config.identity,
+            )));
+        }
+        if self.register_replies != 0 {
+            let index = self.register_replies.trailing_zeros() as usize;
+            self.register_replies &= !(1 << index);
+            let value = self.config.desk.value(Register::ALL[index]);
+            return Some(BaseCommand::HandShake(Command::Reponse(value)));
+        }
+        if let Some(ack) = self.key_acks[0].take() {
+            self.key_acks.rotate_left(1);
+            return Some(BaseCommand::ChangeHeight(Command::Reponse(ack)));
+        }
+        if core::mem::take(&mut self.move_finished) {
+            return Some(BaseCommand::MoveFinished(Command::Command(MoveFinished {})));
+        }
+        let report_due = self
+            .last_report_ms
+            .is_none_or(|last| now_ms.saturating_sub(last) >= self.config.report_ms);
+        if self.connected && report_due {
+            self.last_report_ms = Some(now_ms);
+            return Some(BaseCommand::ReportHeight(Command::Command(
+                ReportHeight::new(self.height()),
+            )));
+        }
+        None
+    }
+
+    fn key(&mut self, key: &ChangeHeight) {
+        let state = *key.state();
+        let preset = Preset::from_key(key);
+        // The captured desk doesn't answer the release of a preset key
+        if state == ChangeHeightState::Start || preset.is_none() {
+            self.ack(key.with_state(ChangeHeightResponse {
+                state,
+                response: ResponseState::Ok,
+            }));
+        }
+        if state == ChangeHeightState::Stop {
+            if self.held {
+                self.stop();
+            }
+            return;
+        }
+        // Any key stops a preset recall
+        if self.is_moving() {
+            self.stop();
+            return;
+        }
+        let limits = self.config.desk.effective_limits();
+        match (key, preset) {
+            (ChangeHeight::Up(_), _) => self.start(limits.max, true),
+            (ChangeHeight::Down(_), _) => self.start(limits.min, true),
+            (_, Some(preset)) => {
+                if let Some(height) = self.presets[preset as usize] {
+                    self.start(limits.clamp(height), false)
+                }
+            }
+            (_, None) => {}
+        }
+    }
+
+    fn ack(&mut self, ack: ChangeHeight<ChangeHeightResponse>) {
+        match self.key_acks.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(ack),
+            // Only the latest matters if the keypad is being ignored
+            None => self.key_acks[MAX_KEY_ACKS - 1] = Some(ack),
+        }
+    }
+
+    fn start(&mut self, target: Height, held: bool) {
+        if target != self.height() {
+            self.target = Some(target);
+            self.held = held;
+        }
+    }
+
+    fn stop(&mut self) {
+        self.target = None;
+        self.held = false;
+        self.move_finished = true;
+    }
+
+    /// Moves the desk for the time since the last tick
+    fn tick(&mut self, now_ms: u64) {
+        let elapsed_ms = now_ms.saturating_sub(self.last_tick_ms.unwrap_or(now_ms));
+        self.last_tick_ms = Some(now_ms);
+        let Some(target) = self.target else {
+            return;
+        };
+        let target_um = target.to_mm() as u32 * 1000;
+        let step_um = (self.config.speed as u64 * elapsed_ms).min(u32::MAX as u64) as u32;
+        self.position_um = if target_um > self.position_um {
+            target_um.min(self.position_um.saturating_add(step_um))
+        } else {
+            target_um.max(self.position_um.saturating_sub(step_um))
+        };
+        if self.position_um == target_um {
+            self.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        height::HeightLimits,
+        new_protocol::Connect,
+        preset::KEY_TAP_MS,
+        session::{ControllerSession, SessionState},
+    };
+
+    fn press(key: ChangeHeight) -> BaseCommand {
+        BaseCommand::ChangeHeight(Command::Command(key))
+    }
+
+    fn drain(desk: &mut DeskSimulator, now_ms: u64) -> Vec<BaseCommand> {
+        core::iter::from_fn(|| desk.poll(now_ms)).collect()
+    }
+
+    fn key_acks(sent: &[BaseCommand]) -> Vec<ChangeHeight<ChangeHeightResponse>> {
+        sent.iter()
+            .filter_map(|command| match command {
+                BaseCommand::ChangeHeight(Command::Reponse(ack)) => Some(*ack),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn finished(sent: &[BaseCommand]) -> bool {
+        sent.iter()
+            .any(|command| matches!(command, BaseCommand::MoveFinished(_)))
+    }
+
+    fn connected(config: SimulatorConfig) -> DeskSimulator {
+        let mut desk = DeskSimulator::new(config);
+        desk.observe(&BaseCommand::Connect(Command::Command(Connect {})), 0);
+        drain(&mut desk, 0);
+        desk
+    }
+
+    #[test]
+    fn session_reaches_ready() {
+        let desk_config = DeskConfig {
+            user_limits: HeightLimits {
+                min: Height::from_mm(700),
+                max: Height::from_mm(1100),
+            },
+            ..DeskConfig::DEFAULT
+        };
+        let mut desk = DeskSimulator::new(SimulatorConfig {
+            desk: desk_config,
+            ..SimulatorConfig::DEFAULT
+        });
+        let mut session = ControllerSession::default();
+        for now_ms in (0..100).step_by(10) {
+            while let Some(command) = session.poll(now_ms) {
+                desk.observe(&command, now_ms);
+            }
+            while let Some(command) = desk.poll(now_ms) {
+                session.observe(&command, now_ms);
+            }
+        }
+        assert!(desk.is_connected());
+        assert_eq!(session.state(), SessionState::Ready);
+        assert_eq!(session.desk_identity(), Some(DeviceIdentity::DESK));
+        assert_eq!(session.desk_config(), Some(desk_config));
+    }
+
+    #[test]
+    fn reports_height_once_connected() {
+        let mut desk = DeskSimulator::default();
+        assert!(drain(&mut desk, 0).is_empty());
+        let mut desk = connected(SimulatorConfig::DEFAULT);
+        assert!(drain(&mut desk, 99).is_empty());
+        assert!(matches!(
+            drain(&mut desk, 100)[..],
+            [BaseCommand::ReportHeight(Command::Command(report))]
+                if report.height == Height::from_mm(724)
+        ));
+    }
+
+    #[test]
+    fn holds_and_releases() {
+        let mut desk = connected(SimulatorConfig::DEFAULT);
+        desk.observe(&press(ChangeHeight::Up(ChangeHeightState::Start)), 0);
+        let ack = ChangeHeight::Up(ChangeHeightResponse {
+            state: ChangeHeightState::Start,
+            response: ResponseState::Ok,
+        });
+        assert_eq!(key_acks(&drain(&mut desk, 0)), [ack]);
+        assert!(desk.is_moving());
+
+        desk.observe(&press(ChangeHeight::Up(ChangeHeightState::Stop)), 2000);
+        let sent = drain(&mut desk, 2000);
+        assert_eq!(key_acks(&sent).len(), 1);
+        assert!(finished(&sent));
+        assert!(!desk.is_moving());
+        assert_eq!(desk.height(), Height::from_mm(774));
+    }
+
+    #[test]
+    fn stops_at_limits() {
+        let mut desk = connected(SimulatorConfig {
+            desk: DeskConfig {
+                user_limits: HeightLimits {
+                    min: Height::from_mm(700),
+                    max: Height::from_mm(1000),
+                },
+                ..DeskConfig::DEFAULT
+            },
+            ..SimulatorConfig::DEFAULT
+        });
+        desk.observe(&press(ChangeHeight::Up(ChangeHeightState::Start)), 0);
+        drain(&mut desk, 0);
+        let sent = drain(&mut desk, 60_000);
+        assert!(finished(&sent));
+        assert!(!desk.is_moving());
+        assert_eq!(desk.height(), Height::from_mm(1000));
+        // The release is still acked but doesn't finish again
+        desk.observe(&press(ChangeHeight::Up(ChangeHeightState::Stop)), 60_000);
+        assert!(!finished(&drain(&mut desk, 60_000)));
+
+        desk.observe(&press(ChangeHeight::Down(ChangeHeightState::Start)), 60_000);
+        drain(&mut desk, 120_000);
+        assert_eq!(desk.height(), Height::from_mm(700));
+    }
+
+    #[test]
+    fn recalls_preset() {
+        let mut desk = connected(SimulatorConfig::DEFAULT);
+        desk.set_preset(Preset::Two, Height::from_mm(824));
+        let [start, stop] = Preset::Two.recall();
+        desk.observe(&press(start), 0);
+        desk.observe(&press(stop), KEY_TAP_MS);
+        // Only the press is acked
+        let sent = drain(&mut desk, KEY_TAP_MS);
+        assert_eq!(key_acks(&sent).len(), 1);
+        assert!(desk.is_moving());
+        assert!(!finished(&sent));
+
+        assert!(finished(&drain(&mut desk, 4000)));
+        assert_eq!(desk.height(), Height::from_mm(824));
+    }
+
+    #[test]
+    fn key_stops_recall() {
+        let mut desk = connected(SimulatorConfig::DEFAULT);
+        desk.set_preset(Preset::One, Height::from_mm(1024));
+        for key in Preset::One.recall() {
+            desk.observe(&press(key), 0);
+        }
+        desk.observe(&press(ChangeHeight::Down(ChangeHeightState::Start)), 1000);
+        assert!(finished(&drain(&mut desk, 1000)));
+        assert!(!desk.is_moving());
+        assert_eq!(desk.height(), Height::from_mm(749));
+    }
+
+    #[test]
+    fn unset_preset_stays_put() {
+        let mut desk = connected(SimulatorConfig::DEFAULT);
+        for key in Preset::Three.recall() {
+            desk.observe(&press(key), 0);
+        }
+        assert!(!desk.is_moving());
+        assert_eq!(desk.preset(Preset::Three), None);
+    }
+}
@@ -0,0 +1,273 @@
+//! Adapters for [`std::io`] so host tools use the same code as the firmware
+//!
+//! [`StdWriter`] lets anything that implements [`std::io::Write`] be passed to
+//! [`encode`](crate::encoder::encode), and [`PacketReader`] turns a [`std::io::Read`] such as a
+//! serial port into an iterator of decoded commands.
+
+use std::{
+    io::{self, ErrorKind, Read},
+    time::Instant,
+};
+
+use crate::{
+    framer::Framer,
+    new_protocol::{BaseCommand, IoError, Write, MAX_PACKET_LEN},
+    profile::DeskProfile,
+};
+
+/// How many bytes are read from the reader at a time
+const READ_CHUNK: usize = 64;
+
+/// Writes packets to a [`std::io::Write`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StdWriter<T>(pub T);
+impl<T> StdWriter<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+impl<T: io::Write> Write for StdWriter<T> {
+    type Error = io::Error;
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+}
+
+/// A command and when the read that finished it returned
+#[derive(Debug, Clone)]
+pub struct TimedCommand {
+    pub at: Instant,
+    pub packet_num: u16,
+    pub command: BaseCommand,
+}
+
+/// Reads and decodes packets from a [`std::io::Read`]
+///
+/// Iterating stops at the end of the reader. An error doesn't end the iteration: the framer has
+/// already resynchronised after a [`IoError::Protocol`], and an [`IoError::Io`] such as a read
+/// timeout can be retried by calling `next` again.
+pub struct PacketReader<R> {
+    reader: R,
+    framer: Framer<MAX_PACKET_LEN>,
+    profile: &'static DeskProfile,
+    buf: [u8; READ_CHUNK],
+    pos: usize,
+    len: usize,
+    read_at: Instant,
+}
+impl<R: Read> PacketReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_profile(reader, &DeskProfile::CAPTURED)
+    }
+
+    pub fn with_profile(reader: R, profile: &'static DeskProfile) -> Self {
+        Self {
+            reader,
+            framer: Framer::with_profile(profile),
+            profile,
+            buf: [0; READ_CHUNK],
+            pos: 0,
+            len: 0,
+            read_at: Instant::now(),
+        }
+    }
+
+    /// Bytes dropped by the framer while looking for packets
+    pub fn discarded(&self) -> usize {
+        self.framer.discarded()
+    }
+
+    /// Drops any partial packet. Call this when the reader reports a parity or framing error
+    pub fn reset(&mut self) {
+        self.framer.reset();
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = Result<TimedCommand, IoError<io::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.pos == self.len {
+                match self.reader.read(&mut self.buf) {
+                    Ok(0) => return None,
+                    Ok(len) => {
+                        self.read_at = Instant::now();
+                        self.pos = 0;
+                        self.len = len;
+                    }
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => return Some(Err(IoError::Io(error))),
+                }
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            let packet = match self.framer.push(byte) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(error) => return Some(Err(error.into())),
+            };
+            let command = self.profile.decode(&packet).map(|command| TimedCommand {
+                at: self.read_at,
+                packet_num: packet.get_packet_num(),
+                command,
+            });
+            return Some(command.map_err(IoError::from));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoder::encode,
+        new_protocol::{
+            tests::wire, ChangeHeight, ChangeHeightState, Command, Payload, ProtocolError,
+        },
+    };
+    use std::{collections::VecDeque, vec::Vec};
+
+    const KEY_PRESS: [u8; 8] = [0xFA, 0x17, 0x03, 0x01, 0x06, 0xCF, 0xDC, 0xFD];
+    const HEARTBEAT: [u8; 9] = [0xFA, 0x01, 0xA0, 0x04, 0x01, 0x59, 0xFE, 0xFD, 0xFD];
+
+    /// Gives back each read in turn, then the end of the input
+    struct Reads(VecDeque<io::Result<Vec<u8>>>);
+    impl Reads {
+        fn new(reads: impl IntoIterator<Item = io::Result<Vec<u8>>>) -> Self {
+            Self(reads.into_iter().collect())
+        }
+    }
+    impl Read for Reads {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(read) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            let bytes = read?;
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    fn packet_nums<R: Read>(reader: PacketReader<R>) -> Vec<u16> {
+        reader.map(|command| command.unwrap().packet_num).collect()
+    }
+
+    #[test]
+    fn reads_captured_packets() {
+        let bytes = [&[0x00, 0xFD][..], &KEY_PRESS, &HEARTBEAT].concat();
+        let mut reader = PacketReader::new(&bytes[..]);
+        let key = reader.next().unwrap().unwrap();
+        assert!(matches!(
+            key.command,
+            BaseCommand::ChangeHeight(Command::Command(ChangeHeight::Up(ChangeHeightState::Start)))
+        ));
+        assert_eq!(key.packet_num, 0x06CF);
+        let heartbeat = reader.next().unwrap().unwrap();
+        assert!(matches!(
+            heartbeat.command,
+            BaseCommand::ReportControllerState(_)
+        ));
+        // The escaped checksum is read back as 0xFD
+        assert_eq!(heartbeat.packet_num, 0x0159);
+        assert!(reader.next().is_none());
+        assert_eq!(reader.discarded(), 2);
+    }
+
+    #[test]
+    fn packets_across_reads() {
+        let reads = Reads::new([
+            Ok(KEY_PRESS[..3].to_vec()),
+            Err(ErrorKind::Interrupted.into()),
+            Ok(KEY_PRESS[3..].to_vec()),
+            Ok(HEARTBEAT.to_vec()),
+        ]);
+        assert_eq!(packet_nums(PacketReader::new(reads)), [0x06CF, 0x0159]);
+    }
+
+    #[test]
+    fn continues_after_a_timeout() {
+        let reads = Reads::new([
+            Ok(KEY_PRESS[..3].to_vec()),
+            Err(ErrorKind::TimedOut.into()),
+            Ok(KEY_PRESS[3..].to_vec()),
+        ]);
+        let mut reader = PacketReader::new(reads);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(IoError::Io(error))) if error.kind() == ErrorKind::TimedOut
+        ));
+        // The partial packet is kept
+        let before = Instant::now();
+        let key = reader.next().unwrap().unwrap();
+        assert_eq!(key.packet_num, 0x06CF);
+        assert!(key.at >= before);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn continues_after_a_decode_error() {
+        // 0x30 isn't a register
+        let query = BaseCommand::Unknown {
+            prefix: 0x15,
+            command_id: 0x30,
+            payload: Payload::EMPTY,
+        };
+        let bytes = [wire(&query, 1), KEY_PRESS.to_vec()].concat();
+        let mut reader = PacketReader::new(&bytes[..]);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(IoError::Protocol(
+                ProtocolError::UnrecognizedRegister { register: 0x30, .. }
+            )))
+        ));
+        assert_eq!(reader.next().unwrap().unwrap().packet_num, 0x06CF);
+    }
+
+    #[test]
+    fn reset_drops_a_partial_packet() {
+        let reads = Reads::new([Ok(KEY_PRESS[..5].to_vec())]);
+        let mut reader = PacketReader::new(reads);
+        assert!(reader.next().is_none());
+        reader.reset();
+        // The start tag and the four bytes after it
+        assert_eq!(reader.discarded(), 5);
+        reader.get_mut().0.push_back(Ok(HEARTBEAT.to_vec()));
+        assert_eq!(reader.next().unwrap().unwrap().packet_num, 0x0159);
+        assert!(reader.get_ref().0.is_empty());
+    }
+
+    #[test]
+    fn writes_packets() {
+        let key = Command::Command(ChangeHeight::Up(ChangeHeightState::Start));
+        let mut writer = StdWriter(Vec::new());
+        encode(&key, 0x06CF, &mut writer).unwrap();
+        encode(&key, 0x06D0, &mut writer).unwrap();
+        let written = writer.into_inner();
+        assert_eq!(written[..KEY_PRESS.len()], KEY_PRESS);
+        assert_eq!(written[KEY_PRESS.len()..], wire(&key, 0x06D0));
+    }
+
+    #[test]
+    fn write_errors_are_passed_on() {
+        let mut buf = [0; 4];
+        let mut writer = StdWriter(&mut buf[..]);
+        let key = Command::Command(ChangeHeight::Up(ChangeHeightState::Start));
+        assert!(matches!(
+            encode(&key, 1, &mut writer),
+            Err(IoError::Io(error)) if error.kind() == ErrorKind::WriteZero
+        ));
+    }
+}
@@ -0,0 +1,717 @@
+//! Sits between the keypad and the desk, forwarding every packet and recording what goes past
+//!
+//! ```text
+//! proxy <keypad port> <desk port> [options]
+//! proxy --simulate [options]
+//!
+//! --out <dir>                  where to write the capture, `proxy-capture` by default
+//! --for <ms>                   stop after this long rather than when a port closes
+//! --drop <side:pattern>        see `rules`, any number of each
+//! --rewrite <side:pattern=body>
+//! --inject <side:ms:body>
+//! --no-turns                   forward packets as soon as they're whole
+//! ```
+//!
+//! Every byte each side sends is written to `controller.csv` or `desk.csv` in the format of the
+//! captures in `data-captures`, and every packet is printed decoded with the time it arrived.
+//! Packets are forwarded once they're whole, renumbered so each side sees an unbroken count
+//! whatever was dropped or injected. Bytes that aren't part of a packet are passed on as they
+//! were. Everything is held until it's the proxy's turn on the line it goes out on, so nothing the
+//! proxy sends talks over the device on that line. With `--no-turns` it goes straight out instead.
+//! Each direction has its own wire, so the devices cope with that.
+//!
+//! `--simulate` runs the keypad and desk simulators on pty pairs in place of the two ports.
+
+mod rules;
+mod simulate;
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use protocol::{
+    bus::{BusConfig, HalfDuplexBus},
+    encoder::encode,
+    framer::Framer,
+    new_protocol::{BaseCommand, PacketBuf, Source, START_TAG},
+    std_io::StdWriter,
+};
+use rules::{raw_command, Action, Injection, Rules};
+use serial::{SerialConfig, SerialPort};
+
+type ThreadResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// How long a read waits so injections and stopping aren't held up by a quiet line
+const READ_TIMEOUT_MS: u64 = 5;
+
+const USAGE: &str =
+    "usage: proxy <keypad port> <desk port> [options] or proxy --simulate [options]";
+
+struct Options {
+    ports: Vec<PathBuf>,
+    simulate: bool,
+    out: PathBuf,
+    run_for: Option<Duration>,
+    rules: Rules,
+    take_turns: bool,
+}
+
+fn parse_args() -> Result<Options, Box<dyn Error>> {
+    let mut options = Options {
+        ports: Vec::new(),
+        simulate: false,
+        out: PathBuf::from("proxy-capture"),
+        run_for: None,
+        rules: Rules::default(),
+        take_turns: true,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--simulate" => options.simulate = true,
+            "--out" => options.out = value()?.into(),
+            "--for" => options.run_for = Some(Duration::from_millis(value()?.parse()?)),
+            "--drop" => options.rules.parse_drop(&value()?)?,
+            "--rewrite" => options.rules.parse_rewrite(&value()?)?,
+            "--inject" => options.rules.parse_inject(&value()?)?,
+            "--no-turns" => options.take_turns = false,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}").into()),
+            _ => options.ports.push(arg.into()),
+        }
+    }
+    let ports = if options.simulate { 0 } else { 2 };
+    if options.ports.len() != ports {
+        return Err(USAGE.into());
+    }
+    Ok(options)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let options = parse_args()?;
+    let running = Arc::new(AtomicBool::new(true));
+    let (keypad, desk, simulation, bus) = if options.simulate {
+        let simulate::Simulation {
+            keypad,
+            desk,
+            session,
+            simulator,
+            threads,
+        } = simulate::start(running.clone())?;
+        (
+            keypad,
+            desk,
+            Some((session, simulator, threads)),
+            simulate::BUS,
+        )
+    } else {
+        let config = SerialConfig {
+            read_timeout: Some(Duration::from_millis(READ_TIMEOUT_MS)),
+            ..SerialConfig::DEFAULT
+        };
+        let keypad = SerialPort::open(&options.ports[0], &config)?;
+        let desk = SerialPort::open(&options.ports[1], &config)?;
+        (keypad, desk, None, BusConfig::DEFAULT)
+    };
+    let bus = BusConfig {
+        take_turns: options.take_turns,
+        ..bus
+    };
+
+    let proxy = Proxy::start(
+        keypad,
+        desk,
+        options.rules,
+        bus,
+        &options.out,
+        running.clone(),
+    )?;
+    while running.load(Ordering::Relaxed)
+        && options
+            .run_for
+            .is_none_or(|run_for| proxy.start.elapsed() < run_for)
+    {
+        thread::sleep(Duration::from_millis(10));
+    }
+    running.store(false, Ordering::Relaxed);
+    proxy.join()?;
+    if let Some((session, simulator, threads)) = simulation {
+        for thread in threads {
+            thread.join().expect("simulator panicked");
+        }
+        println!(
+            "simulated keypad {:?}, desk at {}",
+            session.lock().unwrap().state(),
+            simulator.lock().unwrap().height()
+        );
+    }
+    Ok(())
+}
+
+/// Both directions being forwarded
+struct Proxy {
+    handles: Vec<thread::JoinHandle<ThreadResult>>,
+    keypad_line: Arc<Mutex<HalfDuplexBus>>,
+    desk_line: Arc<Mutex<HalfDuplexBus>>,
+    log: Arc<Mutex<Log>>,
+    start: Instant,
+}
+impl Proxy {
+    /// Starts forwarding between the ports and writing the capture to `out`. Either direction
+    /// stopping clears `running`, which stops the other
+    fn start(
+        keypad: SerialPort,
+        desk: SerialPort,
+        rules: Rules,
+        bus: BusConfig,
+        out: &Path,
+        running: Arc<AtomicBool>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let log = Arc::new(Mutex::new(Log::create(out)?));
+        let rules = Arc::new(rules);
+        let keypad_line = Arc::new(Mutex::new(HalfDuplexBus::new(bus)));
+        let desk_line = Arc::new(Mutex::new(HalfDuplexBus::new(bus)));
+        let start = Instant::now();
+        let (keypad_tx, desk_tx) = (keypad.try_clone()?, desk.try_clone()?);
+        let forwarders = [
+            Forwarder {
+                from: Source::Controller,
+                tx: desk_tx,
+                rx: keypad,
+                framer: Framer::new(),
+                raw: Vec::new(),
+                rx_bus: keypad_line.clone(),
+                tx_bus: desk_line.clone(),
+                colliding: false,
+                rules: rules.clone(),
+                injections: rules.injections_to(Source::Desk),
+                packet_num: None,
+                log: log.clone(),
+                start,
+            },
+            Forwarder {
+                from: Source::Desk,
+                tx: keypad_tx,
+                rx: desk,
+                framer: Framer::new(),
+                raw: Vec::new(),
+                rx_bus: desk_line.clone(),
+                tx_bus: keypad_line.clone(),
+                colliding: false,
+                rules: rules.clone(),
+                injections: rules.injections_to(Source::Controller),
+                packet_num: None,
+                log: log.clone(),
+                start,
+            },
+        ];
+        let mut handles = Vec::new();
+        for mut forwarder in forwarders {
+            let running = running.clone();
+            handles.push(thread::spawn(move || {
+                let result = forwarder.run(&running);
+                // The other direction is no use on its own
+                running.store(false, Ordering::Relaxed);
+                result
+            }));
+        }
+        Ok(Self {
+            handles,
+            keypad_line,
+            desk_line,
+            log,
+            start,
+        })
+    }
+
+    /// Waits for both directions to stop, then finishes the capture and prints the line stats
+    fn join(self) -> Result<(), Box<dyn Error>> {
+        for (handle, source) in self
+            .handles
+            .into_iter()
+            .zip([Source::Controller, Source::Desk])
+        {
+            if let Err(error) = handle.join().expect("forwarder panicked") {
+                println!("{}: {error}", side(source));
+            }
+        }
+        self.log.lock().unwrap().flush()?;
+        println!("keypad line {:?}", self.keypad_line.lock().unwrap().stats());
+        println!("desk line {:?}", self.desk_line.lock().unwrap().stats());
+        Ok(())
+    }
+}
+
+/// Reads from one side and forwards to the other
+struct Forwarder {
+    from: Source,
+    rx: SerialPort,
+    tx: SerialPort,
+    framer: Framer,
+    /// Bytes read since the last packet, so whatever the framer rejects can be passed on as it was
+    raw: Vec<u8>,
+    /// The line `rx` is on, shared with the forwarder writing to it
+    rx_bus: Arc<Mutex<HalfDuplexBus>>,
+    /// The line `tx` is on
+    tx_bus: Arc<Mutex<HalfDuplexBus>>,
+    /// The last byte read collided with the proxy sending
+    colliding: bool,
+    rules: Arc<Rules>,
+    /// Injections for the side `tx` goes to that are still to be sent, soonest first
+    injections: Vec<Injection>,
+    /// The number of the last packet sent to `tx`. Every packet is renumbered so the other side
+    /// sees them in order however many were dropped or injected
+    packet_num: Option<u16>,
+    log: Arc<Mutex<Log>>,
+    start: Instant,
+}
+impl Forwarder {
+    fn run(&mut self, running: &AtomicBool) -> ThreadResult {
+        let mut buf = [0; 64];
+        while running.load(Ordering::Relaxed) {
+            self.inject()?;
+            let len = match self.rx.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(error) if error.kind() == ErrorKind::TimedOut => continue,
+                // The other end closing while stopping isn't worth reporting
+                Err(_) if !running.load(Ordering::Relaxed) => break,
+                Err(error) => return Err(error.into()),
+            };
+            let at = self.start.elapsed();
+            self.log.lock().unwrap().bytes(self.from, at, &buf[..len])?;
+            for &byte in &buf[..len] {
+                let collision = {
+                    // Timed once the bus is locked so both threads' times reach it in order
+                    let mut bus = self.rx_bus.lock().unwrap();
+                    bus.observe(byte, micros(self.start.elapsed()))
+                };
+                match collision {
+                    // Only the first byte of each run of colliding bytes is worth reporting
+                    Some(collision) if !self.colliding => {
+                        println!("{:.4}s {}: {collision:?}", secs(at), side(self.from));
+                    }
+                    _ => {}
+                }
+                self.colliding = collision.is_some();
+                self.raw.push(byte);
+                let packet = match self.framer.push(byte) {
+                    Ok(Some(packet)) => PacketBuf::try_from(packet)?,
+                    Ok(None) => continue,
+                    Err(error) => {
+                        println!("{:.4}s {}: {error}", secs(at), side(self.from));
+                        // The framer has dropped everything it had
+                        let rejected = std::mem::take(&mut self.raw);
+                        self.forward_rejected(&rejected, at)?;
+                        continue;
+                    }
+                };
+                let raw = std::mem::take(&mut self.raw);
+                // Anything in front of the packet was noise or a packet the framer gave up on
+                let rejected = &raw[..raw.len() - self.framer.last_wire_len()];
+                self.forward_rejected(rejected, at)?;
+                self.forward(&packet, at)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn forward(&mut self, packet: &PacketBuf, at: Duration) -> ThreadResult {
+        let bytes = packet.as_bytes();
+        // Without the start tag, and the packet number, checksum and end tag
+        let body = &bytes[1..bytes.len() - 4];
+        let decoded = match BaseCommand::decode(&packet.as_packet()) {
+            Ok(command) => format!("{command:?}"),
+            Err(error) => error.to_string(),
+        };
+        let rules = self.rules.clone();
+        let body = match rules.action(self.from, body) {
+            Some(Action::Drop) => {
+                println!("{:.4}s {}: {decoded} dropped", secs(at), side(self.from));
+                return Ok(());
+            }
+            Some(Action::Rewrite(rewritten)) => {
+                println!(
+                    "{:.4}s {}: {decoded} rewritten to {rewritten:02x?}",
+                    secs(at),
+                    side(self.from)
+                );
+                rewritten
+            }
+            None => {
+                println!("{:.4}s {}: {decoded}", secs(at), side(self.from));
+                body
+            }
+        };
+        // The first packet keeps its number so the other side sees the numbering it would have
+        let packet_num = self
+            .packet_num
+            .map_or(packet.get_packet_num(), |last| last.wrapping_add(1));
+        self.send(&raw_command(body)?, packet_num)
+    }
+
+    /// Passes on bytes the framer couldn't make a packet of, unless a drop rule matches them
+    fn forward_rejected(&mut self, bytes: &[u8], at: Duration) -> ThreadResult {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let body = bytes.strip_prefix(&[START_TAG]).unwrap_or(bytes);
+        if self.rules.action(self.from, body) == Some(&Action::Drop) {
+            println!("{:.4}s {}: {bytes:02x?} dropped", secs(at), side(self.from));
+            return Ok(());
+        }
+        println!(
+            "{:.4}s {}: {bytes:02x?} isn't a packet, forwarded as is",
+            secs(at),
+            side(self.from)
+        );
+        self.write(bytes)
+    }
+
+    fn inject(&mut self) -> ThreadResult {
+        let now = self.start.elapsed();
+        while self
+            .injections
+            .first()
+            .is_some_and(|injection| injection.at_ms <= now.as_millis() as u64)
+        {
+            let injection = self.injections.remove(0);
+            let command = raw_command(&injection.body)?;
+            println!(
+                "{:.4}s proxy: injecting {:02x?} to the {}",
+                secs(now),
+                injection.body,
+                side(injection.to)
+            );
+            // The captured keypad counts from 1 when it connects
+            let packet_num = self.packet_num.map_or(1, |last| last.wrapping_add(1));
+            self.send(&command, packet_num)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a packet numbered `packet_num`, which becomes the last number sent
+    fn send(&mut self, command: &BaseCommand, packet_num: u16) -> ThreadResult {
+        let mut wire = StdWriter(Vec::new());
+        encode(command, packet_num, &mut wire)?;
+        self.packet_num = Some(packet_num);
+        self.write(&wire.into_inner())
+    }
+
+    /// Writes bytes once it's the proxy's turn on the line, if it's taking turns
+    fn write(&mut self, bytes: &[u8]) -> ThreadResult {
+        loop {
+            let (claimed, now_us) = {
+                let mut bus = self.tx_bus.lock().unwrap();
+                let now_us = micros(self.start.elapsed());
+                (bus.start_transmit(bytes, now_us), now_us)
+            };
+            match claimed {
+                Ok(()) => break,
+                Err(busy) => thread::sleep(Duration::from_micros(busy.ready_at_us - now_us)),
+            }
+        }
+        self.tx.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// The bytes each side sent, in the format of the captures in `data-captures`
+struct Log {
+    controller: csv::Writer<File>,
+    desk: csv::Writer<File>,
+    /// The time of the last byte written to each file
+    last: [f64; 2],
+}
+impl Log {
+    fn create(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        std::fs::create_dir_all(dir)?;
+        let create = |file: &str| -> csv::Result<csv::Writer<File>> {
+            let mut writer = csv::Writer::from_path(dir.join(file))?;
+            writer.write_record(["Time [s]", "Value", "Parity Error", "Framing Error"])?;
+            Ok(writer)
+        };
+        Ok(Self {
+            controller: create("controller.csv")?,
+            desk: create("desk.csv")?,
+            last: [0.; 2],
+        })
+    }
+
+    /// Records the bytes from one read. The last arrived at `at` and the others a byte apart
+    /// before it, but never before the previous read's
+    fn bytes(&mut self, source: Source, at: Duration, bytes: &[u8]) -> csv::Result<()> {
+        let (writer, last) = match source {
+            Source::Controller => (&mut self.controller, &mut self.last[0]),
+            Source::Desk => (&mut self.desk, &mut self.last[1]),
+        };
+        let byte_s = BusConfig::DEFAULT.byte_us as f64 / 1_000_000.;
+        for (index, byte) in bytes.iter().enumerate() {
+            let time = secs(at) - (bytes.len() - 1 - index) as f64 * byte_s;
+            let time = time.max(*last);
+            *last = time;
+            writer.write_record([
+                format!("{time:.15}"),
+                format!("0x{byte:02X}"),
+                "".into(),
+                "".into(),
+            ])?;
+        }
+        // Kept up to date so a capture cut short with ctrl-c is still complete
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.controller.flush()?;
+        self.desk.flush()
+    }
+}
+
+fn side(source: Source) -> &'static str {
+    match source {
+        Source::Desk => "desk",
+        Source::Controller => "keypad",
+    }
+}
+
+fn secs(at: Duration) -> f64 {
+    at.as_secs_f64()
+}
+
+fn micros(at: Duration) -> u64 {
+    at.as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{
+        config::DeskConfig,
+        height::Height,
+        new_protocol::{ChangeHeight, ChangeHeightState, Command},
+        session::{ControllerSession, SessionState},
+        simulator::DeskSimulator,
+    };
+    use serial::pty_pair;
+
+    use super::*;
+
+    fn out_dir(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("proxy-{}-{test}", std::process::id()))
+    }
+
+    /// Runs the simulators through the proxy until `done` or 3s have passed
+    fn simulate(
+        test: &str,
+        rules: Rules,
+        done: impl Fn(&ControllerSession, &DeskSimulator) -> bool,
+    ) -> (ControllerSession, DeskSimulator) {
+        let running = Arc::new(AtomicBool::new(true));
+        let simulation = simulate::start(running.clone()).unwrap();
+        let out = out_dir(test);
+        let proxy = Proxy::start(
+            simulation.keypad,
+            simulation.desk,
+            rules,
+            simulate::BUS,
+            &out,
+            running.clone(),
+        )
+        .unwrap();
+        while proxy.start.elapsed() < Duration::from_secs(3)
+            && !done(
+                &simulation.session.lock().unwrap(),
+                &simulation.simulator.lock().unwrap(),
+            )
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        running.store(false, Ordering::Relaxed);
+        proxy.join().unwrap();
+        for thread in simulation.threads {
+            thread.join().unwrap();
+        }
+        // Both sides were recorded
+        for file in ["controller.csv", "desk.csv"] {
+            let capture = std::fs::read_to_string(out.join(file)).unwrap();
+            assert!(capture.lines().count() > 1, "{file} is empty");
+        }
+        std::fs::remove_dir_all(out).unwrap();
+        let session = simulation.session.lock().unwrap().clone();
+        let simulator = simulation.simulator.lock().unwrap().clone();
+        (session, simulator)
+    }
+
+    fn ready(session: &ControllerSession, _: &DeskSimulator) -> bool {
+        session.state() == SessionState::Ready
+    }
+
+    #[test]
+    fn reaches_ready() {
+        let (session, simulator) = simulate("ready", Rules::default(), ready);
+        assert_eq!(session.state(), SessionState::Ready);
+        assert_eq!(session.desk_config(), Some(DeskConfig::DEFAULT));
+        assert!(simulator.is_connected());
+    }
+
+    #[test]
+    fn drop_stops_handshake() {
+        let mut rules = Rules::default();
+        // Asking for the user minimum height
+        rules.parse_drop("keypad:1573").unwrap();
+        let (session, simulator) = simulate("drop", rules, |_, _| false);
+        assert!(simulator.is_connected());
+        assert_ne!(session.state(), SessionState::Ready);
+        assert_eq!(session.desk_config(), None);
+    }
+
+    #[test]
+    fn rewrite_changes_config() {
+        let mut rules = Rules::default();
+        rules.parse_rewrite("desk:1621=16210320").unwrap();
+        let (session, _) = simulate("rewrite", rules, ready);
+        let config = session.desk_config().unwrap();
+        assert_eq!(config.limits.min, Height::from_mm(800));
+        assert_eq!(config.effective_limits().min, Height::from_mm(800));
+    }
+
+    #[test]
+    fn inject_moves_desk() {
+        let mut rules = Rules::default();
+        rules.parse_inject("desk:300:170301").unwrap();
+        rules.parse_inject("desk:1300:170300").unwrap();
+        let (session, simulator) = simulate("inject", rules, |_, simulator| {
+            simulator.height() > Height::from_mm(724) && !simulator.is_moving()
+        });
+        assert_eq!(session.state(), SessionState::Ready);
+        assert!(simulator.height() > Height::from_mm(724));
+        assert!(!simulator.is_moving());
+    }
+
+    /// The proxy between two pty pairs, with the test writing to and reading from the masters
+    struct PtyProxy {
+        keypad: SerialPort,
+        desk: SerialPort,
+        proxy: Proxy,
+        running: Arc<AtomicBool>,
+        out: PathBuf,
+    }
+    impl PtyProxy {
+        fn start(test: &str, rules: Rules) -> Self {
+            let config = SerialConfig {
+                read_timeout: Some(Duration::from_millis(READ_TIMEOUT_MS)),
+                ..SerialConfig::DEFAULT
+            };
+            let keypad = pty_pair(&config).unwrap();
+            let desk = pty_pair(&config).unwrap();
+            let running = Arc::new(AtomicBool::new(true));
+            let out = out_dir(test);
+            let proxy = Proxy::start(
+                keypad.slave,
+                desk.slave,
+                rules,
+                simulate::BUS,
+                &out,
+                running.clone(),
+            )
+            .unwrap();
+            Self {
+                keypad: keypad.master,
+                desk: desk.master,
+                proxy,
+                running,
+                out,
+            }
+        }
+
+        /// Reads `len` bytes from the desk's side, or whatever arrived in 3s
+        fn read_desk(&mut self, len: usize) -> Vec<u8> {
+            let mut bytes = vec![0; len];
+            let mut read = 0;
+            let start = Instant::now();
+            while read < len && start.elapsed() < Duration::from_secs(3) {
+                match self.desk.read(&mut bytes[read..]) {
+                    Ok(len) => read += len,
+                    Err(error) if error.kind() == ErrorKind::TimedOut => {}
+                    Err(error) => panic!("{error}"),
+                }
+            }
+            bytes.truncate(read);
+            bytes
+        }
+
+        fn stop(self) {
+            self.running.store(false, Ordering::Relaxed);
+            self.proxy.join().unwrap();
+            std::fs::remove_dir_all(self.out).unwrap();
+        }
+    }
+
+    fn encoded(command: &BaseCommand, packet_num: u16) -> Vec<u8> {
+        let mut wire = StdWriter(Vec::new());
+        encode(command, packet_num, &mut wire).unwrap();
+        wire.into_inner()
+    }
+
+    #[test]
+    fn renumbers_and_forwards_rejected_bytes() {
+        let mut rules = Rules::default();
+        rules.parse_inject("desk:0:170301").unwrap();
+        rules.parse_drop("keypad:99").unwrap();
+        let mut pty = PtyProxy::start("renumber", rules);
+        let key = |state, packet_num| {
+            let command = BaseCommand::ChangeHeight(Command::Command(ChangeHeight::Up(state)));
+            encoded(&command, packet_num)
+        };
+
+        // Counts from 1 when nothing has been forwarded yet
+        let injected = key(ChangeHeightState::Start, 1);
+        assert_eq!(pty.read_desk(injected.len()), injected);
+
+        let noise = [0x00, 0x01, 0x00, 0x00, 0xFF];
+        pty.keypad
+            .write_all(
+                &[
+                    &noise[..],
+                    &key(ChangeHeightState::Start, 0x0500),
+                    &[0xFA, 0x99, 0xFD],
+                    &key(ChangeHeightState::Stop, 0x0501),
+                ]
+                .concat(),
+            )
+            .unwrap();
+        let expected = [
+            &noise[..],
+            &key(ChangeHeightState::Start, 2),
+            &key(ChangeHeightState::Stop, 3),
+        ]
+        .concat();
+        assert_eq!(pty.read_desk(expected.len()), expected);
+        pty.stop();
+    }
+
+    #[test]
+    fn forwards_noise_in_front_of_unescaped_packet() {
+        let mut pty = PtyProxy::start("unescaped", Rules::default());
+        let noise = [0x00, 0x12];
+        // The framer takes the bare start tag in the payload as data, so the packet is shorter on
+        // the wire than it is once the proxy escapes it
+        let packet = [0xFA, 0x03, 0x00, 0x01, 0x02, 0xFA, 0x0D, 0x31, 0xC6, 0xFD];
+        pty.keypad
+            .write_all(&[&noise[..], &packet].concat())
+            .unwrap();
+        let body = &packet[1..packet.len() - 4];
+        let expected = [&noise[..], &encoded(&raw_command(body).unwrap(), 0x0D31)].concat();
+        assert_eq!(pty.read_desk(expected.len()), expected);
+        pty.stop();
+    }
+}
@@ -0,0 +1,145 @@
+//! What the proxy does to packets besides forwarding them
+//!
+//! Packets are matched and written as hex bodies: the prefix, command id and payload without the
+//! tags, packet number or checksum, which the proxy fills in. `17 03 01` is Up being pressed.
+//! Bytes that aren't part of a packet are matched from after their start tag, if they have one.
+//!
+//! - `--drop keypad:01` drops every packet from the keypad whose body starts with `01`
+//! - `--rewrite keypad:170301=170401` sends the desk `17 04 01` instead of `17 03 01`
+//! - `--inject desk:2000:170301` sends the desk `17 03 01` two seconds after the proxy starts
+
+use std::error::Error;
+
+use protocol::{
+    encoder::encode_packet,
+    new_protocol::{BaseCommand, Payload, ProtocolResult, Source},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    /// Send this body in place of the matched one
+    Rewrite(Vec<u8>),
+}
+
+/// Applies to every packet from `from` whose body starts with `pattern`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub from: Source,
+    pub pattern: Vec<u8>,
+    pub action: Action,
+}
+
+/// A packet sent to `to` once, `at_ms` after the proxy starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Injection {
+    pub to: Source,
+    pub at_ms: u64,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+    pub injections: Vec<Injection>,
+}
+impl Rules {
+    /// The first rule matching a packet, in the order they were given
+    pub fn action(&self, from: Source, body: &[u8]) -> Option<&Action> {
+        self.rules
+            .iter()
+            .find(|rule| rule.from == from && body.starts_with(&rule.pattern))
+            .map(|rule| &rule.action)
+    }
+
+    /// The injections for `to`, soonest first
+    pub fn injections_to(&self, to: Source) -> Vec<Injection> {
+        let mut injections = self
+            .injections
+            .iter()
+            .filter(|injection| injection.to == to)
+            .cloned()
+            .collect::<Vec<_>>();
+        injections.sort_by_key(|injection| injection.at_ms);
+        injections
+    }
+
+    /// `side:pattern`
+    pub fn parse_drop(&mut self, arg: &str) -> Result<(), Box<dyn Error>> {
+        let (from, pattern) = arg.split_once(':').ok_or("expected side:pattern")?;
+        self.rules.push(Rule {
+            from: parse_side(from)?,
+            pattern: parse_hex(pattern)?,
+            action: Action::Drop,
+        });
+        Ok(())
+    }
+
+    /// `side:pattern=body`
+    pub fn parse_rewrite(&mut self, arg: &str) -> Result<(), Box<dyn Error>> {
+        let (from, rest) = arg.split_once(':').ok_or("expected side:pattern=body")?;
+        let (pattern, body) = rest.split_once('=').ok_or("expected side:pattern=body")?;
+        let body = parse_hex(body)?;
+        check_body(&body)?;
+        self.rules.push(Rule {
+            from: parse_side(from)?,
+            pattern: parse_hex(pattern)?,
+            action: Action::Rewrite(body),
+        });
+        Ok(())
+    }
+
+    /// `side:ms:body`
+    pub fn parse_inject(&mut self, arg: &str) -> Result<(), Box<dyn Error>> {
+        let mut parts = arg.splitn(3, ':');
+        let (Some(to), Some(at_ms), Some(body)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("expected side:ms:body".into());
+        };
+        let body = parse_hex(body)?;
+        check_body(&body)?;
+        self.injections.push(Injection {
+            to: parse_side(to)?,
+            at_ms: at_ms.parse()?,
+            body,
+        });
+        Ok(())
+    }
+}
+
+/// A packet body as a command that writes it back out unchanged
+pub fn raw_command(body: &[u8]) -> ProtocolResult<BaseCommand> {
+    Ok(BaseCommand::Unknown {
+        prefix: body[0],
+        command_id: body[1],
+        payload: Payload::new(&body[2..])?,
+    })
+}
+
+/// Makes sure a body the proxy was given would be understood by the other side
+fn check_body(body: &[u8]) -> Result<(), Box<dyn Error>> {
+    if body.len() < 2 {
+        return Err("a body needs at least a prefix and a command id".into());
+    }
+    let packet = encode_packet(&raw_command(body)?, 0)?;
+    BaseCommand::decode(&packet.as_packet())?;
+    Ok(())
+}
+
+fn parse_side(side: &str) -> Result<Source, Box<dyn Error>> {
+    match side {
+        "keypad" | "controller" => Ok(Source::Controller),
+        "desk" => Ok(Source::Desk),
+        side => Err(format!("unknown side {side}, expected keypad or desk").into()),
+    }
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let hex = hex.replace(' ', "");
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(format!("{hex} isn't a whole number of hex bytes").into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| Ok(u8::from_str_radix(&hex[at..at + 2], 16)?))
+        .collect()
+}
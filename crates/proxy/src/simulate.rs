@@ -0,0 +1,130 @@
+//! Stands a simulated keypad and desk up on pseudo-terminals so the proxy can run without hardware
+//!
+//! The keypad is a [`ControllerSession`] and the desk a [`DeskSimulator`], each on the master end of
+//! its own pty pair. The proxy gets the slave ends, exactly as it would get two USB-UART adapters.
+//! Both are shared so their state can be looked at while they run.
+
+use std::{
+    error::Error,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use protocol::{
+    bus::BusConfig,
+    new_protocol::{BaseCommand, DeviceIdentity, IoError, Source},
+    session::{ControllerSession, SessionConfig},
+    simulator::DeskSimulator,
+};
+use serial::{pty_pair, SerialConfig, SerialPort};
+
+/// How long the simulators wait for a packet before polling again
+const POLL_MS: u64 = 5;
+
+/// A pty has no baud rate, everything written arrives at once
+pub const BUS: BusConfig = BusConfig {
+    byte_us: 0,
+    turnaround_us: 0,
+    ..BusConfig::DEFAULT
+};
+
+/// Either end's state machine
+trait Device: Send + 'static {
+    fn observe(&mut self, command: &BaseCommand, now_ms: u64);
+    fn poll(&mut self, now_ms: u64) -> Option<BaseCommand>;
+}
+impl Device for ControllerSession {
+    fn observe(&mut self, command: &BaseCommand, now_ms: u64) {
+        ControllerSession::observe(self, command, now_ms)
+    }
+
+    fn poll(&mut self, now_ms: u64) -> Option<BaseCommand> {
+        ControllerSession::poll(self, now_ms)
+    }
+}
+impl Device for DeskSimulator {
+    fn observe(&mut self, command: &BaseCommand, now_ms: u64) {
+        DeskSimulator::observe(self, command, now_ms)
+    }
+
+    fn poll(&mut self, now_ms: u64) -> Option<BaseCommand> {
+        DeskSimulator::poll(self, now_ms)
+    }
+}
+
+/// The ports the proxy should use, and the simulators and the threads running them
+pub struct Simulation {
+    pub keypad: SerialPort,
+    pub desk: SerialPort,
+    pub session: Arc<Mutex<ControllerSession>>,
+    pub simulator: Arc<Mutex<DeskSimulator>>,
+    pub threads: Vec<JoinHandle<()>>,
+}
+
+pub fn start(running: Arc<AtomicBool>) -> Result<Simulation, Box<dyn Error>> {
+    let config = SerialConfig {
+        read_timeout: Some(Duration::from_millis(POLL_MS)),
+        ..SerialConfig::DEFAULT
+    };
+    let keypad = pty_pair(&config)?;
+    let desk = pty_pair(&config)?;
+    let session = Arc::new(Mutex::new(ControllerSession::new(
+        DeviceIdentity::KEYPAD,
+        SessionConfig::DEFAULT,
+    )));
+    let simulator = Arc::new(Mutex::new(DeskSimulator::default()));
+    let threads = vec![
+        run(
+            Source::Controller,
+            session.clone(),
+            keypad.master,
+            running.clone(),
+        )?,
+        run(Source::Desk, simulator.clone(), desk.master, running)?,
+    ];
+    Ok(Simulation {
+        keypad: keypad.slave,
+        desk: desk.slave,
+        session,
+        simulator,
+        threads,
+    })
+}
+
+fn run(
+    source: Source,
+    device: Arc<Mutex<impl Device>>,
+    port: SerialPort,
+    running: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut tx = port.try_clone()?;
+    Ok(thread::spawn(move || {
+        let start = Instant::now();
+        let now_ms = || start.elapsed().as_millis() as u64;
+        let mut packet_num: u16 = match source {
+            // Where the desk in the `connect` capture was counting from
+            Source::Desk => 0x1700,
+            Source::Controller => 0,
+        };
+        let mut packets = port.packets();
+        while running.load(Ordering::Relaxed) {
+            match packets.next() {
+                Some(Ok(packet)) => device.lock().unwrap().observe(&packet.command, now_ms()),
+                Some(Err(IoError::Io(error))) if error.kind() == ErrorKind::TimedOut => {}
+                Some(Err(IoError::Protocol(error))) => println!("simulated {source:?}: {error}"),
+                Some(Err(_)) | None => break,
+            }
+            while let Some(command) = device.lock().unwrap().poll(now_ms()) {
+                packet_num = packet_num.wrapping_add(1);
+                if tx.send(&command, packet_num).is_err() {
+                    return;
+                }
+            }
+        }
+    }))
+}
@@ -0,0 +1,275 @@
+//! Opens a Linux serial port for the desk protocol
+//!
+//! [`SerialPort`] sets a tty up with termios the way the desk's uart is set up and implements
+//! [`std::io::Read`] and [`std::io::Write`], so it plugs straight into the `protocol` crate's
+//! [`PacketReader`] and encoder. [`pty_pair`] gives two connected pseudo-terminals that behave
+//! the same way, so anything using a port can be run without a USB-UART adapter.
+
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    os::fd::{AsFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use nix::{
+    fcntl::{open, OFlag},
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    pty::openpty,
+    sys::{
+        stat::Mode,
+        termios::{
+            cfmakeraw, cfsetspeed, tcflush, tcgetattr, tcsetattr, BaudRate, ControlFlags, FlushArg,
+            SetArg, SpecialCharacterIndices,
+        },
+    },
+    unistd::ttyname,
+};
+use protocol::{
+    encoder::encode,
+    new_protocol::{IoError, Writeable},
+    std_io::{PacketReader, StdWriter},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// How the line is set up. Data is always 8 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// How long a read waits for the first byte. `None` waits forever
+    pub read_timeout: Option<Duration>,
+}
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+impl SerialConfig {
+    /// The captured link. Its bytes are 86.5us apart, 10 bits each at 115200 baud
+    pub const DEFAULT: Self = Self {
+        baud: 115_200,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        read_timeout: Some(Duration::from_millis(100)),
+    };
+}
+
+/// A tty in raw mode
+#[derive(Debug)]
+pub struct SerialPort {
+    file: File,
+    read_timeout: Option<Duration>,
+}
+impl SerialPort {
+    pub fn open(path: impl AsRef<Path>, config: &SerialConfig) -> io::Result<Self> {
+        let fd = open(
+            path.as_ref(),
+            OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        // SAFETY: `open` just returned this descriptor and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Self::from_fd(fd, config)
+    }
+
+    fn from_fd(fd: OwnedFd, config: &SerialConfig) -> io::Result<Self> {
+        let mut port = Self {
+            file: File::from(fd),
+            read_timeout: None,
+        };
+        port.configure(config)?;
+        // Anything that arrived before the port was set up was read with the wrong settings
+        tcflush(port.file.as_fd(), FlushArg::TCIOFLUSH)?;
+        Ok(port)
+    }
+
+    pub fn configure(&mut self, config: &SerialConfig) -> io::Result<()> {
+        let mut termios = tcgetattr(self.file.as_fd())?;
+        cfmakeraw(&mut termios);
+        cfsetspeed(&mut termios, baud_rate(config.baud)?)?;
+        let flags = &mut termios.control_flags;
+        flags.insert(ControlFlags::CLOCAL | ControlFlags::CREAD);
+        flags.remove(ControlFlags::PARENB | ControlFlags::PARODD | ControlFlags::CSTOPB);
+        match config.parity {
+            Parity::None => {}
+            Parity::Even => flags.insert(ControlFlags::PARENB),
+            Parity::Odd => flags.insert(ControlFlags::PARENB | ControlFlags::PARODD),
+        }
+        if config.stop_bits == StopBits::Two {
+            flags.insert(ControlFlags::CSTOPB);
+        }
+        // Reads block for at least one byte, the timeout is done with poll
+        termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        termios.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        tcsetattr(self.file.as_fd(), SetArg::TCSANOW, &termios)?;
+        self.read_timeout = config.read_timeout;
+        Ok(())
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Another handle to the same port, so one thread can read while another writes
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            read_timeout: self.read_timeout,
+        })
+    }
+
+    /// Encodes `command` and writes it in one go
+    pub fn send<C: Writeable>(
+        &mut self,
+        command: &C,
+        packet_num: u16,
+    ) -> Result<(), IoError<io::Error>> {
+        let mut wire = StdWriter(Vec::new());
+        encode(command, packet_num, &mut wire)?;
+        self.write_all(&wire.into_inner()).map_err(IoError::Io)
+    }
+
+    /// Decoded packets from the port. A read timeout comes out as an [`ErrorKind::TimedOut`]
+    pub fn packets(self) -> PacketReader<Self> {
+        PacketReader::new(self)
+    }
+}
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(timeout) = self.read_timeout {
+            let timeout = PollTimeout::try_from(timeout)
+                .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "read timeout too long"))?;
+            let mut fds = [PollFd::new(self.file.as_fd(), PollFlags::POLLIN)];
+            if poll(&mut fds, timeout)? == 0 {
+                return Err(ErrorKind::TimedOut.into());
+            }
+        }
+        self.file.read(buf)
+    }
+}
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn baud_rate(baud: u32) -> io::Result<BaudRate> {
+    Ok(match baud {
+        9_600 => BaudRate::B9600,
+        19_200 => BaudRate::B19200,
+        38_400 => BaudRate::B38400,
+        57_600 => BaudRate::B57600,
+        115_200 => BaudRate::B115200,
+        230_400 => BaudRate::B230400,
+        460_800 => BaudRate::B460800,
+        921_600 => BaudRate::B921600,
+        baud => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported baud rate {baud}"),
+            ))
+        }
+    })
+}
+
+/// Two connected pseudo-terminals, both set up with the same config
+///
+/// Whatever is written to one end is read from the other. The baud rate, parity and stop bits
+/// don't change anything on a pty but are checked and set all the same.
+#[derive(Debug)]
+pub struct PtyPair {
+    pub master: SerialPort,
+    pub slave: SerialPort,
+    /// Where the slave end can be opened by another program, `/dev/pts/N`
+    pub slave_path: PathBuf,
+}
+
+pub fn pty_pair(config: &SerialConfig) -> io::Result<PtyPair> {
+    let pty = openpty(None, None)?;
+    let slave_path = ttyname(pty.slave.as_fd())?;
+    Ok(PtyPair {
+        master: SerialPort::from_fd(pty.master, config)?,
+        slave: SerialPort::from_fd(pty.slave, config)?,
+        slave_path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::new_protocol::{BaseCommand, ChangeHeight, ChangeHeightState, Command};
+
+    use super::*;
+
+    fn key_press() -> BaseCommand {
+        BaseCommand::ChangeHeight(Command::Command(ChangeHeight::Up(ChangeHeightState::Start)))
+    }
+
+    #[test]
+    fn sends_packets_both_ways() {
+        let pty = pty_pair(&SerialConfig::DEFAULT).unwrap();
+        let (mut master, mut slave) = (pty.master, pty.slave.try_clone().unwrap());
+        master.send(&key_press(), 0x06CF).unwrap();
+        let received = pty.slave.packets().next().unwrap().unwrap();
+        assert_eq!(received.packet_num, 0x06CF);
+        assert!(matches!(
+            received.command,
+            BaseCommand::ChangeHeight(Command::Command(ChangeHeight::Up(ChangeHeightState::Start)))
+        ));
+
+        slave.send(&key_press(), 0x06D0).unwrap();
+        let received = master.packets().next().unwrap().unwrap();
+        assert_eq!(received.packet_num, 0x06D0);
+    }
+
+    #[test]
+    fn read_times_out() {
+        let config = SerialConfig {
+            read_timeout: Some(Duration::from_millis(10)),
+            ..SerialConfig::DEFAULT
+        };
+        let pty = pty_pair(&config).unwrap();
+        let mut master = pty.master;
+        let mut packets = pty.slave.packets();
+        assert!(matches!(
+            packets.next(),
+            Some(Err(IoError::Io(error))) if error.kind() == ErrorKind::TimedOut
+        ));
+        // Still usable after a timeout
+        master.send(&key_press(), 1).unwrap();
+        assert_eq!(packets.next().unwrap().unwrap().packet_num, 1);
+    }
+
+    #[test]
+    fn rejects_unsupported_baud_rates() {
+        assert!(matches!(baud_rate(115_200), Ok(BaudRate::B115200)));
+        let error = baud_rate(100_000).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "unsupported baud rate 100000");
+
+        let config = SerialConfig {
+            baud: 250_000,
+            ..SerialConfig::DEFAULT
+        };
+        let error = pty_pair(&config).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}